@@ -7,6 +7,12 @@ use crate::bus::OAM_SIZE;
 pub const NUM_OBJ: usize = 40;
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
+const OAM_ROWS: usize = 20; // mode 2 reads OAM one 8 byte row per machine cycle
+// How long each mode lasts in T-cycles, VBlank is per line
+const OAM_CYCLES: u32 = 80;
+const PIXEL_CYCLES: u32 = 172;
+const HBLANK_CYCLES: u32 = 204;
+const LINE_CYCLES: u32 = 456;
 
 #[derive(Eq, PartialEq, Clone, Copy)]
 pub enum Palette {
@@ -29,6 +35,14 @@ pub enum Modes {
     Pixel,
 }
 
+/// How the CPU touched FE00-FEFF while mode 2 was scanning OAM, which decides how the row gets garbled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OamCorruption {
+    Write, // a write, or the 16-bit INC/DEC putting the address on the bus
+    Read,
+    ReadWithIncrement, // LD A,(HL+) and LD A,(HL-)
+}
+
 impl Interrupt {
     fn add(&mut self, other: Interrupt) {
         match self {
@@ -84,9 +98,10 @@ pub struct GPU {
     pub object_data: [Object; NUM_OBJ],
     pub lcd: LCD,
     pub modes: Modes,
-    pub cycles: u32,
     pub lyc_flag: bool,
     pub lyc_interrupt_bool: bool, // Renamed to avoid confusion with Interrupt enum
+    stat_line: bool, // the selected STAT sources ORed together, the interrupt fires when this rises
+    blanked: bool, // STOP keeps the screen blank, lines are timed as usual but not drawn
 }
 
 impl GPU {
@@ -108,9 +123,10 @@ impl GPU {
             object_data,
             lcd: LCD::new(),
             modes: Modes::OAM,
-            cycles: 0,
             lyc_flag: false,
             lyc_interrupt_bool: false,
+            stat_line: false,
+            blanked: false,
         }
     }
     pub fn write_oam(&mut self, index: usize, value: u8) {
@@ -133,6 +149,55 @@ impl GPU {
         }
     }
 
+    /// The DMG's OAM corruption bug, see https://gbdev.io/pandocs/OAM_Corruption_Bug.html.
+    /// Only the row mode 2 is reading `mode_cycles` into it is affected, and never the first one
+    pub fn corrupt_oam(&mut self, kind: OamCorruption, mode_cycles: u64) {
+        if !self.lcd.control.lcd_ppu_enable() || !matches!(self.modes, Modes::OAM) {
+            return;
+        }
+        let row = (mode_cycles / 4) as usize;
+        if row == 0 || row >= OAM_ROWS {
+            return;
+        }
+        if kind == OamCorruption::ReadWithIncrement && (4..OAM_ROWS - 1).contains(&row) {
+            let a = self.oam_word(row - 2, 0);
+            let b = self.oam_word(row - 1, 0);
+            let c = self.oam_word(row, 0);
+            let d = self.oam_word(row - 1, 2);
+            self.set_oam_word(row - 1, 0, (b & (a | c | d)) | (a & c & d));
+            self.copy_oam_row(row - 1, row);
+            self.copy_oam_row(row - 1, row - 2);
+        }
+        let (a, b, c) = (self.oam_word(row, 0), self.oam_word(row - 1, 0), self.oam_word(row - 1, 2));
+        let first = match kind {
+            OamCorruption::Write => ((a ^ c) & (b ^ c)) ^ c,
+            OamCorruption::Read | OamCorruption::ReadWithIncrement => b | (a & c),
+        };
+        self.set_oam_word(row, 0, first);
+        // the rest of the row is replaced by the one before it
+        for word in 1..4 {
+            self.set_oam_word(row, word, self.oam_word(row - 1, word));
+        }
+    }
+
+    fn oam_word(&self, row: usize, word: usize) -> u16 {
+        let index = row * 8 + word * 2;
+        u16::from_le_bytes([self.oam[index], self.oam[index + 1]])
+    }
+
+    fn set_oam_word(&mut self, row: usize, word: usize, value: u16) {
+        let index = row * 8 + word * 2;
+        let [low, high] = value.to_le_bytes();
+        self.write_oam(index, low);
+        self.write_oam(index + 1, high);
+    }
+
+    fn copy_oam_row(&mut self, from: usize, to: usize) {
+        for word in 0..4 {
+            self.set_oam_word(to, word, self.oam_word(from, word));
+        }
+    }
+
     pub fn write_vram(&mut self, addr: usize, value: u8) {
         self.vram[addr] = value;
         let tile_index = addr / 16;
@@ -155,68 +220,119 @@ impl GPU {
         self.tiles[tile_index].set_color(packed);
     }
 
-    pub fn update(&mut self, machine_cycles: u8) -> Interrupt {
-        let mut interrupt_request = Interrupt::None;
-        if !self.lcd.control.lcd_ppu_enable() {
-            self.cycles = 0;
-            self.lcd.ly = 0;
-            self.modes = Modes::VBlank;
-            return interrupt_request;
+    /// Fills the screen with the lightest shade and keeps it that way until `unblank_screen`, used
+    /// while the LCD isn't being driven (e.g. during STOP)
+    pub fn blank_screen(&mut self) {
+        self.blanked = true;
+        if let Some(color) = Palette::BGP.map_color(0) {
+            for pixel in self.canvas_buffer.chunks_exact_mut(4) {
+                pixel.copy_from_slice(&[color.r, color.g, color.b, 255]);
+            }
         }
+    }
 
-        self.cycles += machine_cycles as u32;
+    pub fn unblank_screen(&mut self) {
+        self.blanked = false;
+    }
 
-        match self.modes {
-            Modes::OAM => {
-                if self.cycles >= 80 {
-                    self.cycles -= 80;
-                    self.modes = Modes::Pixel;
-                }
+    /// What the LCD is left at while it's off, turning it back on starts a new frame from here with
+    /// line 0's OAM scan
+    pub fn disable(&mut self) {
+        self.lcd.ly = 0;
+        self.modes = Modes::OAM;
+        self.check_line_comparison();
+        self.stat_line = false;
+    }
+
+    /// STAT as the CPU reads it, the select bits it was written with plus the current LY=LYC flag
+    /// and mode, which reads as 0 while the LCD is off
+    pub fn stat(&self) -> u8 {
+        let mode = if self.lcd.control.lcd_ppu_enable() {
+            match self.modes {
+                Modes::HBlank => 0,
+                Modes::VBlank => 1,
+                Modes::OAM => 2,
+                Modes::Pixel => 3,
             }
+        } else {
+            0
+        };
+        0x80 | (self.lcd.status & 0b0111_1000) | ((self.lyc_flag as u8) << 2) | mode
+    }
+
+    /// T-cycles the current mode lasts for
+    pub fn mode_length(&self) -> u32 {
+        match self.modes {
+            Modes::OAM => OAM_CYCLES,
+            Modes::Pixel => PIXEL_CYCLES,
+            Modes::HBlank => HBLANK_CYCLES,
+            // LY only reads 153 for the first M-cycle of the last line, it's 0 for the rest of it
+            Modes::VBlank => match self.lcd.ly {
+                153 => 4,
+                0 => LINE_CYCLES - 4,
+                _ => LINE_CYCLES,
+            },
+        }
+    }
+
+    /// Moves on once the current mode (or VBlank line) is over, the bus calls this `mode_length` after
+    /// the last time and schedules the next call for the new `mode_length`
+    pub fn next_mode(&mut self) -> Interrupt {
+        let mut interrupt_request = Interrupt::None;
+        match self.modes {
+            Modes::OAM => self.modes = Modes::Pixel,
             Modes::Pixel => {
-                if self.cycles >= 172 {
-                    self.cycles -= 172;
-                    self.modes = Modes::HBlank;
-                    self.render_scanline();
-                }
+                self.modes = Modes::HBlank;
+                self.render_scanline();
             }
             Modes::HBlank => {
-                if self.cycles >= 204 {
-                    self.cycles -= 204;
-                    self.lcd.ly += 1;
-                    if self.lcd.ly >= 144 {
-                        self.modes = Modes::VBlank;
-                        interrupt_request.add(Interrupt::VBlank);
-                    } else {
-                        self.modes = Modes::OAM;
-                    }
-                    self.check_line_comparison(&mut interrupt_request);
+                self.lcd.ly += 1;
+                if self.lcd.ly >= 144 {
+                    self.modes = Modes::VBlank;
+                    interrupt_request.add(Interrupt::VBlank);
+                } else {
+                    self.modes = Modes::OAM;
                 }
+                self.check_line_comparison();
             }
             Modes::VBlank => {
-                if self.cycles >= 456 {
-                    self.cycles -= 456;
-                    self.lcd.ly += 1;
-                    if self.lcd.ly > 153 {
-                        self.lcd.ly = 0;
-                        self.modes = Modes::OAM;
-                    }
-                    self.check_line_comparison(&mut interrupt_request);
+                match self.lcd.ly {
+                    153 => self.lcd.ly = 0,
+                    0 => self.modes = Modes::OAM,
+                    _ => self.lcd.ly += 1,
                 }
+                self.check_line_comparison();
             }
         }
+        self.update_stat_line(&mut interrupt_request);
         interrupt_request
     }
 
-    fn check_line_comparison(&mut self, interrupt_request: &mut Interrupt) {
-        let coincidence = self.lcd.ly == self.lcd.lyc;
-        if coincidence && self.lyc_interrupt_bool {
+    fn check_line_comparison(&mut self) {
+        self.lyc_flag = self.lcd.ly == self.lcd.lyc;
+    }
+
+    /// Requests LCDStat when one of the sources selected in STAT bits 3-6 becomes true while none
+    /// of the others already was, so a mode change right after LY=LYC doesn't fire twice
+    fn update_stat_line(&mut self, interrupt_request: &mut Interrupt) {
+        let select = self.lcd.status;
+        let mode = match self.modes {
+            Modes::HBlank => select & 0b0000_1000 != 0,
+            Modes::VBlank => select & 0b0001_0000 != 0,
+            Modes::OAM => select & 0b0010_0000 != 0,
+            Modes::Pixel => false,
+        };
+        let line = mode || (self.lyc_flag && self.lyc_interrupt_bool);
+        if line && !self.stat_line {
             interrupt_request.add(Interrupt::LCDStat);
         }
-        self.lyc_flag = coincidence;
+        self.stat_line = line;
     }
 
     fn render_scanline(&mut self) {
+        if self.blanked {
+            return;
+        }
         let mut bg_pixel_ids = [0u8; SCREEN_WIDTH];
         let line = self.lcd.ly;
         let lcdc = self.lcd.control;
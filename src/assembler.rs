@@ -0,0 +1,557 @@
+//! A small SM83 assembler, so CPU tests can be written as readable source instead of hand-assembled hex.
+//!
+//! Every instruction the decoder knows is written the way `Display for Instruction` renders it
+//! (`ld a, n8`, `ldh [n16], a`, `jr nz, e8`, `ld hl, sp+e8`, ...) with the placeholders replaced by
+//! expressions: numbers ($ff, 0xff, %1010, 0b1010, 255), labels, and sums and differences of those.
+//! The usual RGBDS shorthands are accepted as well: `and b` for `and a, b`, `[hli]`/`[hld]`,
+//! `ldi`/`ldd`, `ld a, [c]`, `ldh [c], a` and `jp [hl]`.
+//!
+//! Labels are declared with a trailing colon and may be used before they're declared. Everything is
+//! case insensitive and `;` starts a comment. The directives are `db` and `dw` (comma separated
+//! values), `ds count[, fill]` and `org address`.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::LazyLock;
+use crate::instruction::Instruction;
+use crate::opcodes;
+
+pub const ROM_SIZE: usize = 0x8000;
+pub const ENTRY_POINT: u16 = 0x0100;
+const HEADER_BEGIN: u16 = 0x0104;
+const HEADER_END: u16 = 0x014F;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize, // 1-based
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// Assembles `source` as if it were loaded at `origin`, gaps left by `org` or `ds` are zero filled
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AssembleError> {
+    let chunks = Assembler::new(origin).run(source)?;
+    let end = chunks.iter().map(|(_, address, bytes)| *address as usize + bytes.len()).max().unwrap_or(origin as usize);
+    let mut output = vec![0; end.saturating_sub(origin as usize)];
+    for (line, address, bytes) in chunks {
+        if address < origin {
+            return Err(AssembleError { line, message: format!("${:04x} is below the origin ${:04x}", address, origin) });
+        }
+        let start = (address - origin) as usize;
+        output[start..start + bytes.len()].copy_from_slice(&bytes);
+    }
+    Ok(output)
+}
+
+/// Assembles `source` into a 32KiB ROM-only image for `Cartridge::from_rom`. Code starts at the
+/// 0x0100 entry point where `CPU::new` begins, use `org` to put handlers at the RST and interrupt
+/// vectors. The header is left zeroed, so code has to jump over 0x0104-0x014F
+pub fn assemble_rom(source: &str) -> Result<Vec<u8>, AssembleError> {
+    let chunks = Assembler::new(ENTRY_POINT).run(source)?;
+    let mut rom = vec![0; ROM_SIZE];
+    for (line, address, bytes) in chunks {
+        let end = address as usize + bytes.len();
+        if end > ROM_SIZE {
+            return Err(AssembleError { line, message: format!("${:04x} is outside the ROM", end - 1) });
+        }
+        if address <= HEADER_END && end > HEADER_BEGIN as usize {
+            return Err(AssembleError { line, message: "overlaps the cartridge header at $0104-$014f".to_string() });
+        }
+        rom[address as usize..end].copy_from_slice(&bytes);
+    }
+    Ok(rom)
+}
+
+/// How a template operand is matched and encoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pattern {
+    Literal(String), // a register, condition or `[hl+]` style operand, compared as is
+    Number(u16),     // the fixed numbers in `rst $38` and `bit 3, a`
+    Imm8,
+    Imm16,
+    Mem16,    // [n16]
+    HighPage, // [n16] of LDH, only the low byte is encoded
+    Relative, // JR's e8, written as the address to jump to
+    Signed8,  // ADD SP's e8
+    SpOffset, // sp+e8
+}
+
+struct Template {
+    operands: Vec<Pattern>,
+    opcode: u8,
+    prefixed: bool,
+    length: u16,
+}
+
+/// Every decodable opcode, grouped by mnemonic, built from the disassembler's own rendering so the
+/// two can never disagree on syntax
+static TEMPLATES: LazyLock<HashMap<String, Vec<Template>>> = LazyLock::new(|| {
+    let mut templates: HashMap<String, Vec<Template>> = HashMap::new();
+    for prefixed in [false, true] {
+        for opcode in 0..=0xFF {
+            let Some(entry) = opcodes::lookup(opcode, prefixed) else {
+                continue;
+            };
+            if let Instruction::ILLEGAL(_) = entry.instruction {
+                continue;
+            }
+            let text = entry.instruction.to_string();
+            let (mnemonic, operands) = text.split_once(' ').unwrap_or((&text, ""));
+            let operands = split_operands(operands)
+                .into_iter()
+                .map(|operand| template_pattern(mnemonic, &operand))
+                .collect();
+            templates.entry(mnemonic.to_string()).or_default().push(Template {
+                operands,
+                opcode,
+                prefixed,
+                length: entry.instruction.length(),
+            });
+        }
+    }
+    templates
+});
+
+fn template_pattern(mnemonic: &str, operand: &str) -> Pattern {
+    match operand {
+        "n8" => Pattern::Imm8,
+        "n16" => Pattern::Imm16,
+        "[n16]" if mnemonic == "ldh" => Pattern::HighPage,
+        "[n16]" => Pattern::Mem16,
+        "e8" if mnemonic == "jr" => Pattern::Relative,
+        "e8" => Pattern::Signed8,
+        "sp+e8" => Pattern::SpOffset,
+        _ => match parse_number(operand) {
+            Some(number) => Pattern::Number(number),
+            None => Pattern::Literal(operand.to_string()),
+        },
+    }
+}
+
+/// Splits on commas and drops whitespace, operands never contain spaces
+fn split_operands(operands: &str) -> Vec<String> {
+    if operands.trim().is_empty() {
+        return Vec::new();
+    }
+    operands
+        .split(',')
+        .map(|operand| operand.chars().filter(|c| !c.is_whitespace()).collect())
+        .collect()
+}
+
+const RESERVED: [&str; 15] = ["a", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "sp", "nz", "z", "nc"];
+const ALU: [&str; 8] = ["add", "adc", "sub", "sbc", "and", "or", "xor", "cp"];
+
+/// An operand value, resolved once every label is known
+#[derive(Debug, Clone)]
+struct Expression {
+    terms: Vec<(bool, Term)>, // (negated, term)
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(u16),
+    Label(String),
+}
+
+impl Expression {
+    fn parse(text: &str) -> Option<Expression> {
+        let mut terms = Vec::new();
+        let mut rest = text;
+        let mut negated = false;
+        loop {
+            if let Some(stripped) = rest.strip_prefix('-') {
+                negated = !negated;
+                rest = stripped;
+                continue;
+            }
+            if let Some(stripped) = rest.strip_prefix('+') {
+                rest = stripped;
+                continue;
+            }
+            let end = rest.find(['+', '-']).unwrap_or(rest.len());
+            let (token, tail) = rest.split_at(end);
+            terms.push((negated, Term::parse(token)?));
+            if tail.is_empty() {
+                return Some(Expression { terms });
+            }
+            negated = false;
+            rest = tail;
+        }
+    }
+
+    /// Kept signed and unwrapped so the caller can check it fits the operand
+    fn evaluate(&self, labels: &HashMap<String, u16>) -> Result<i32, String> {
+        let mut total: i32 = 0;
+        for (negated, term) in &self.terms {
+            let value = match term {
+                Term::Number(number) => *number as i32,
+                Term::Label(name) => *labels.get(name).ok_or_else(|| format!("unknown label `{}`", name))? as i32,
+            };
+            total += if *negated { -value } else { value };
+        }
+        Ok(total)
+    }
+
+    fn is_constant(&self) -> bool {
+        self.terms.iter().all(|(_, term)| matches!(term, Term::Number(_)))
+    }
+}
+
+impl Term {
+    fn parse(token: &str) -> Option<Term> {
+        if let Some(number) = parse_number(token) {
+            return Some(Term::Number(number));
+        }
+        let mut chars = token.chars();
+        let valid_start = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.');
+        if !valid_start || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') || RESERVED.contains(&token) {
+            return None;
+        }
+        Some(Term::Label(token.to_string()))
+    }
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    let (digits, radix) = if let Some(hex) = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        (hex, 16)
+    } else if let Some(binary) = text.strip_prefix('%').or_else(|| text.strip_prefix("0b")) {
+        (binary, 2)
+    } else {
+        (text, 10)
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    u16::from_str_radix(digits, radix).ok()
+}
+
+/// What a line turned into after the first pass, waiting for labels to be resolved
+enum Item {
+    Instruction { template: &'static Template, values: Vec<(Pattern, Expression)> },
+    Data { values: Vec<Expression>, width: usize },
+    Fill { count: u16, value: u8 },
+}
+
+struct Assembler {
+    address: u16,
+    labels: HashMap<String, u16>,
+    items: Vec<(usize, u16, Item)>, // (line, address, item)
+}
+
+impl Assembler {
+    fn new(origin: u16) -> Self {
+        Assembler {
+            address: origin,
+            labels: HashMap::new(),
+            items: Vec::new(),
+        }
+    }
+
+    /// Returns (line, address, bytes) for everything that was emitted
+    fn run(mut self, source: &str) -> Result<Vec<(usize, u16, Vec<u8>)>, AssembleError> {
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            self.first_pass(line_number, line).map_err(|message| AssembleError { line: line_number, message })?;
+        }
+        self.items
+            .iter()
+            .map(|(line, address, item)| {
+                self.encode(*address, item)
+                    .map(|bytes| (*line, *address, bytes))
+                    .map_err(|message| AssembleError { line: *line, message })
+            })
+            .collect()
+    }
+
+    fn first_pass(&mut self, line_number: usize, line: &str) -> Result<(), String> {
+        let line = line.split(';').next().unwrap_or("").trim().to_lowercase();
+        let mut rest = line.as_str();
+        // any number of labels can come before the statement
+        while let Some((label, tail)) = rest.split_once(':') {
+            let label = label.trim();
+            if !matches!(Term::parse(label), Some(Term::Label(_))) {
+                return Err(format!("`{}` isn't a valid label", label));
+            }
+            if self.labels.insert(label.to_string(), self.address).is_some() {
+                return Err(format!("label `{}` is declared twice", label));
+            }
+            rest = tail.trim_start();
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+        let (mnemonic, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let operands = split_operands(operands);
+        let item = match mnemonic {
+            "org" => {
+                let [address] = operands.as_slice() else {
+                    return Err("org takes one address".to_string());
+                };
+                self.address = self.constant(address)?;
+                return Ok(());
+            }
+            "db" | "dw" => {
+                let values = operands
+                    .iter()
+                    .map(|operand| Expression::parse(operand).ok_or_else(|| format!("bad value `{}`", operand)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Item::Data { values, width: if mnemonic == "db" { 1 } else { 2 } }
+            }
+            "ds" => {
+                let (count, value) = match operands.as_slice() {
+                    [count] => (self.constant(count)?, 0),
+                    [count, value] => (self.constant(count)?, self.constant(value)? as u8),
+                    _ => return Err("ds takes a count and an optional fill byte".to_string()),
+                };
+                Item::Fill { count, value }
+            }
+            _ => self.instruction(mnemonic, operands)?,
+        };
+        let size = match &item {
+            Item::Instruction { template, .. } => template.length,
+            Item::Data { values, width } => (values.len() * width) as u16,
+            Item::Fill { count, .. } => *count,
+        };
+        self.items.push((line_number, self.address, item));
+        self.address = self.address.wrapping_add(size);
+        Ok(())
+    }
+
+    /// org and ds have to be known in the first pass, only labels declared above them can be used
+    fn constant(&self, text: &str) -> Result<u16, String> {
+        let expression = Expression::parse(text).ok_or_else(|| format!("bad value `{}`", text))?;
+        expression.evaluate(&self.labels).map(|value| value as u16)
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: Vec<String>) -> Result<Item, String> {
+        let (mnemonic, operands) = normalize(mnemonic, operands);
+        let templates = TEMPLATES.get(&mnemonic).ok_or_else(|| format!("unknown instruction `{}`", mnemonic))?;
+        for template in templates {
+            if template.operands.len() != operands.len() {
+                continue;
+            }
+            let matched: Option<Vec<(Pattern, Expression)>> = template
+                .operands
+                .iter()
+                .zip(&operands)
+                .map(|(pattern, operand)| match_operand(pattern, operand))
+                .collect::<Option<Vec<_>>>()
+                .map(|values| values.into_iter().flatten().collect());
+            if let Some(values) = matched {
+                return Ok(Item::Instruction { template, values });
+            }
+        }
+        Err(format!("no form of `{}` takes `{}`", mnemonic, operands.join(", ")))
+    }
+
+    fn encode(&self, address: u16, item: &Item) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        match item {
+            Item::Instruction { template, values } => {
+                if template.prefixed {
+                    bytes.push(0xCB);
+                }
+                bytes.push(template.opcode);
+                for (pattern, expression) in values {
+                    let value = expression.evaluate(&self.labels)?;
+                    match pattern {
+                        Pattern::Imm8 => bytes.push(in_range(value, -128, 0xFF)? as u8),
+                        Pattern::Imm16 | Pattern::Mem16 => {
+                            bytes.extend((in_range(value, -0x8000, 0xFFFF)? as u16).to_le_bytes())
+                        }
+                        Pattern::HighPage => {
+                            let value = in_range(value, 0, 0xFFFF)?;
+                            if value > 0xFF && value < 0xFF00 {
+                                return Err(format!("${:04x} isn't in $ff00-$ffff", value));
+                            }
+                            bytes.push(value as u8);
+                        }
+                        Pattern::Relative => {
+                            let offset = value - (address as i32 + template.length as i32);
+                            bytes.push(in_range(offset, -128, 127).map_err(|_| "jump target is out of reach".to_string())? as u8);
+                        }
+                        Pattern::Signed8 | Pattern::SpOffset => bytes.push(in_range(value, -128, 127)? as u8),
+                        Pattern::Literal(_) | Pattern::Number(_) => {}
+                    }
+                }
+                // STOP's padding byte
+                bytes.resize(template.length as usize, 0);
+            }
+            Item::Data { values, width } => {
+                for expression in values {
+                    let value = expression.evaluate(&self.labels)?;
+                    if *width == 1 {
+                        bytes.push(in_range(value, -128, 0xFF)? as u8);
+                    } else {
+                        bytes.extend((in_range(value, -0x8000, 0xFFFF)? as u16).to_le_bytes());
+                    }
+                }
+            }
+            Item::Fill { count, value } => bytes.resize(*count as usize, *value),
+        }
+        Ok(bytes)
+    }
+}
+
+fn in_range(value: i32, min: i32, max: i32) -> Result<i32, String> {
+    if value < min || value > max {
+        return Err(format!("{} doesn't fit", value));
+    }
+    Ok(value)
+}
+
+/// Rewrites the RGBDS shorthands into the forms the disassembler prints
+fn normalize(mnemonic: &str, mut operands: Vec<String>) -> (String, Vec<String>) {
+    let mut mnemonic = mnemonic.to_string();
+    for operand in operands.iter_mut() {
+        match operand.as_str() {
+            "[hli]" => *operand = "[hl+]".to_string(),
+            "[hld]" => *operand = "[hl-]".to_string(),
+            "[c]" => *operand = "[$ff00+c]".to_string(),
+            _ => {}
+        }
+    }
+    match mnemonic.as_str() {
+        "ldi" | "ldd" => {
+            let replacement = if mnemonic == "ldi" { "[hl+]" } else { "[hl-]" };
+            for operand in operands.iter_mut().filter(|operand| *operand == "[hl]") {
+                *operand = replacement.to_string();
+            }
+            mnemonic = "ld".to_string();
+        }
+        "ldh" if operands.iter().any(|operand| operand == "[$ff00+c]") => mnemonic = "ld".to_string(),
+        "jp" if operands == ["[hl]"] => operands = vec!["hl".to_string()],
+        _ if ALU.contains(&mnemonic.as_str()) && operands.len() == 1 => operands.insert(0, "a".to_string()),
+        _ => {}
+    }
+    (mnemonic, operands)
+}
+
+/// None when the operand doesn't fit the pattern, Some(None) when it fits and carries no value
+fn match_operand(pattern: &Pattern, operand: &str) -> Option<Option<(Pattern, Expression)>> {
+    let expression = match pattern {
+        Pattern::Literal(literal) => return (literal == operand).then_some(None),
+        Pattern::Number(number) => {
+            let expression = Expression::parse(operand)?;
+            let matches = expression.is_constant() && expression.evaluate(&HashMap::new()).ok()? == *number as i32;
+            return matches.then_some(None);
+        }
+        Pattern::Imm8 | Pattern::Imm16 | Pattern::Relative | Pattern::Signed8 => Expression::parse(operand)?,
+        Pattern::Mem16 | Pattern::HighPage => {
+            Expression::parse(operand.strip_prefix('[')?.strip_suffix(']')?)?
+        }
+        Pattern::SpOffset => {
+            let offset = operand.strip_prefix("sp")?;
+            if !offset.starts_with(['+', '-']) {
+                return None;
+            }
+            Expression::parse(offset)?
+        }
+    };
+    Some(Some((pattern.clone(), expression)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::disassemble_one;
+
+    #[test]
+    fn reassembles_every_disassembled_opcode() {
+        let address = 0xC000;
+        for prefixed in [false, true] {
+            for opcode in 0..=0xFF {
+                let Some(entry) = opcodes::lookup(opcode, prefixed) else {
+                    continue;
+                };
+                if matches!(entry.instruction, Instruction::ILLEGAL(_) | Instruction::STOP()) {
+                    continue;
+                }
+                let mut bytes = if prefixed { vec![0xCB, opcode] } else { vec![opcode, 0xF5, 0xFF] };
+                bytes.truncate(entry.instruction.length() as usize);
+                let line = disassemble_one(&bytes, address);
+                assert_eq!(assemble(&line.text, address), Ok(bytes), "{}", line.text);
+            }
+        }
+    }
+
+    #[test]
+    fn resolves_labels_in_both_directions() {
+        let source = "
+            start:  ld hl, data      ; forward reference
+                    jr nz, start
+            loop:   dec b
+                    jp nz, loop
+            data:   db $12, -1, %101
+                    dw start+1
+        ";
+        let expected = vec![
+            0x21, 0x09, 0x01, // ld hl, $0109
+            0x20, 0xFB,       // jr nz, $0100
+            0x05,             // dec b
+            0xC2, 0x05, 0x01, // jp nz, $0105
+            0x12, 0xFF, 0x05,
+            0x01, 0x01,
+        ];
+        assert_eq!(assemble(source, 0x0100), Ok(expected));
+    }
+
+    #[test]
+    fn accepts_rgbds_shorthands() {
+        let source = "
+            and b
+            cp $10
+            ldi a, [hl]
+            ld [hld], a
+            ldh [c], a
+            ld a, [c]
+            ldh [$ff44], a
+            ldh a, [$44]
+            jp [hl]
+            add sp, -2
+            ld hl, sp-2
+            rst $38
+            bit 7, [hl]
+            stop
+        ";
+        let expected = vec![
+            0xA0, 0xFE, 0x10, 0x2A, 0x32, 0xE2, 0xF2, 0xE0, 0x44, 0xF0, 0x44, 0xE9, 0xE8, 0xFE, 0xF8, 0xFE,
+            0xFF, 0xCB, 0x7E, 0x10, 0x00,
+        ];
+        assert_eq!(assemble(source, 0), Ok(expected));
+    }
+
+    #[test]
+    fn places_rom_code_at_the_entry_point_and_vectors() {
+        let rom = assemble_rom("
+                    jp main
+            org $40
+                    reti
+            org $150
+            main:   halt
+        ").unwrap();
+        assert_eq!(rom.len(), ROM_SIZE);
+        assert_eq!(&rom[0x100..0x103], &[0xC3, 0x50, 0x01]);
+        assert_eq!(rom[0x40], 0xD9);
+        assert_eq!(rom[0x150], 0x76);
+        assert_eq!(rom[0x147], 0x00); // ROM only
+    }
+
+    #[test]
+    fn reports_the_offending_line() {
+        let error = |source| assemble(source, 0).unwrap_err();
+        assert_eq!(error("nop\nld q, 1").line, 2);
+        assert_eq!(error("jp nowhere").message, "unknown label `nowhere`");
+        assert_eq!(error("ld a, 256").message, "256 doesn't fit");
+        assert_eq!(error("frob a").message, "unknown instruction `frob`");
+        assert_eq!(error("here: jr here+200").message, "jump target is out of reach");
+        assert!(assemble_rom("ds 8").is_err());
+    }
+}
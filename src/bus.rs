@@ -1,5 +1,15 @@
-use crate::GPU::gpu::GPU; // Adjust path as needed
+use std::fs::File;
+use std::io::Read;
+use std::ops::RangeInclusive;
+use crate::GPU::gpu::{GPU, Interrupt, Modes, OamCorruption};
 use crate::cartride::Cartridge;
+use crate::device::{BusDevice, Devices};
+use crate::dma::Dma;
+use crate::interrupts::{InterruptController, InterruptSource};
+use crate::keypad::{Key, Keypad};
+use crate::model::Model;
+use crate::scheduler::{Event, Scheduler};
+use crate::serial::{self, Serial};
 use crate::timer::Timer;
 
 pub const VRAM_BEGIN: usize = 0x8000;
@@ -7,9 +17,13 @@ pub const VRAM_END: usize = 0x9FFF;
 pub const VRAM_SIZE: usize = VRAM_END - VRAM_BEGIN + 1;
 
 pub const WRAM_BEGIN: usize = 0xC000;
-pub const WRAM_END: usize = 0xCFFF;
+pub const WRAM_END: usize = 0xDFFF;
 pub const WRAM_SIZE: usize = WRAM_END - WRAM_BEGIN + 1;
 
+// E000-FDFF mirrors C000-DDFF
+pub const ECHO_BEGIN: usize = 0xE000;
+pub const ECHO_END: usize = 0xFDFF;
+
 pub const SWITCH_BEGIN: usize = 0xA000;
 pub const SWITCH_END: usize = 0xBFFF;
 pub const SWITCH_SIZE: usize = SWITCH_END - SWITCH_BEGIN + 1;
@@ -26,37 +40,333 @@ pub const HRAM_BEGIN: usize = 0xFF80;
 pub const HRAM_END: usize = 0xFFFE;
 pub const HRAM_SIZE: usize = HRAM_END - HRAM_BEGIN + 1; //test
 
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900; // 0x0100-0x01FF is a hole where the cartridge header shows through
+
+/// Everything the CPU needs from the other side of the bus, `MemoryBus` is the real machine
+/// and the test harnesses can swap in plain memory
+pub trait Bus {
+    fn read_byte(&self, address: u16) -> u8;
+    fn write_byte(&mut self, address: u16, value: u8);
+    /// Advances everything on the bus besides the CPU by `cycles` T-cycles
+    fn tick(&mut self, cycles: u8);
+    fn interrupts(&self) -> &InterruptController;
+    fn interrupts_mut(&mut self) -> &mut InterruptController;
+
+    /// The ROM bank mapped at 4000-7FFF, used to report where a fault happened
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+    /// Whether a selected joypad line has gone from high to low since STOP was entered, which is
+    /// what ends it
+    fn take_joypad_edge(&mut self) -> bool {
+        false
+    }
+    /// STOP resets DIV and blanks the LCD
+    fn enter_stop(&mut self) {}
+    /// DIV stays at 0 for as long as STOP lasts, and the LCD shows pictures again
+    fn exit_stop(&mut self) {}
+    fn has_boot_rom(&self) -> bool {
+        false
+    }
+    /// Puts the hardware back the way it powers on and maps the boot ROM in, as happens when the
+    /// console is reset
+    fn power_on(&mut self) {}
+    /// Puts the IO registers into the state the boot ROM of `model` leaves them in
+    fn set_post_boot_state(&mut self, _model: Model) {}
+    /// The CPU put `address` on the bus in a way that trips the DMG's OAM bug when it's in FE00-FEFF
+    fn trigger_oam_bug(&mut self, _address: u16, _kind: OamCorruption) {}
+}
+
 pub struct MemoryBus {
     pub gpu: GPU,         // Bus now owns the GPU
     pub timer: Timer,
+    pub dma: Dma,
+    pub keypad: Keypad,
+    pub serial: Serial,
     pub cartridge: Cartridge,
     pub wram_bank: [u8; WRAM_SIZE],
     pub hram: [u8; HRAM_SIZE],
     pub io: [u8; IO_SIZE],
-    pub interrupt_enable: u8,
+    pub interrupts: InterruptController, // IF ($FF0F), IE ($FFFF) and IME
+    boot_rom: Option<Vec<u8>>,
+    boot_rom_mapped: bool, // cleared for good by a write to $FF50
+    pub fixed_ly: Option<u8>, // pins LY ($FF44), Gameboy Doctor's reference logs expect it stuck at 0x90
+    cycles: u64, // T-cycles since power on
+    frames: u64, // VBlanks since power on
+    scheduler: Scheduler,
+    ppu_mode_started: u64, // when the GPU entered its current mode
+    devices: Devices,
+    joypad_edge: bool, // a key press has pulled a selected line low since STOP was entered
 }
 
 impl MemoryBus {
     pub fn new(cartridge: Cartridge) -> Self {
-        Self {
+        let mut bus = Self {
             gpu: GPU::new(),              // Initializes VRAM, OAM, and Tiles
             timer: Timer::new(),          // Your Timer implementation
+            dma: Dma::new(),
+            keypad: Keypad::new(),
+            serial: Serial::new(),
             cartridge: cartridge,         // The loaded game ROM
             wram_bank: [0; WRAM_SIZE],
             hram: [0; HRAM_SIZE],
             io: [0; IO_SIZE],
-            interrupt_enable: 0,
+            interrupts: InterruptController::new(),
+            boot_rom: None,
+            boot_rom_mapped: false,
+            fixed_ly: None,
+            cycles: 0,
+            frames: 0,
+            scheduler: Scheduler::new(),
+            ppu_mode_started: 0,
+            devices: Devices::new(),
+            joypad_edge: false,
+        };
+        bus.restart_ppu();
+        bus
+    }
+
+    /// Loads a DMG (256 byte) or CGB (2304 byte) boot ROM dump and maps it over the cartridge
+    pub fn load_boot_rom(&mut self, filename: &str) -> Result<(), String> {
+        let mut file = File::open(filename).map_err(|e| e.to_string())?;
+        let mut boot_rom = Vec::new();
+        file.read_to_end(&mut boot_rom).map_err(|e| e.to_string())?;
+        self.set_boot_rom(boot_rom)
+    }
+
+    /// Maps an already loaded boot ROM image over the cartridge
+    pub fn set_boot_rom(&mut self, boot_rom: Vec<u8>) -> Result<(), String> {
+        if boot_rom.len() != DMG_BOOT_ROM_SIZE && boot_rom.len() != CGB_BOOT_ROM_SIZE {
+            return Err(format!("Unexpected boot ROM size: {} bytes", boot_rom.len()));
+        }
+        self.boot_rom = Some(boot_rom);
+        self.boot_rom_mapped = true;
+        Ok(())
+    }
+
+    fn read_boot_rom(&self, address: u16) -> Option<u8> {
+        if !self.boot_rom_mapped {
+            return None;
+        }
+        let boot_rom = self.boot_rom.as_ref()?;
+        match address as usize {
+            addr @ 0x0000..=0x00FF => Some(boot_rom[addr]),
+            addr @ 0x0200..=0x08FF if boot_rom.len() == CGB_BOOT_ROM_SIZE => Some(boot_rom[addr]),
+            _ => None,
+        }
+    }
+
+    /// T-cycles the machine has run for since it was powered on, the timestamp everything else
+    /// (traces, movies, save states) should agree on
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Frames the LCD has finished since power on, counted at the start of each VBlank
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Hands every read and write in `range` to `device` instead of the built in hardware, which
+    /// stays where it is underneath, see the `device` module for the details
+    pub fn map_device(&mut self, range: RangeInclusive<u16>, device: impl BusDevice + 'static) {
+        self.devices.map(range, Box::new(device));
+    }
+
+    /// Picks the GPU back up from whatever mode it's in, nothing runs while the LCD is off
+    fn restart_ppu(&mut self) {
+        if !self.gpu.lcd.control.lcd_ppu_enable() {
+            self.scheduler.cancel(Event::PpuModeChange);
+            return;
+        }
+        self.ppu_mode_started = self.cycles;
+        self.scheduler.schedule(Event::PpuModeChange, self.cycles + self.gpu.mode_length() as u64);
+    }
+
+    fn write_lcd_control(&mut self, value: u8) {
+        let was_enabled = self.gpu.lcd.control.lcd_ppu_enable();
+        self.gpu.lcd.control.raw = value;
+        if was_enabled != self.gpu.lcd.control.lcd_ppu_enable() {
+            // turning the LCD on starts from where turning it off left it, with a fresh dot count
+            self.gpu.disable();
+            self.restart_ppu();
+        }
+    }
+
+    fn write_serial_control(&mut self, value: u8) {
+        if self.serial.write_control(value) {
+            self.scheduler.schedule(Event::SerialTransfer, self.cycles + serial::CYCLES_PER_BYTE);
+        } else {
+            // clearing the start bit, or handing the clock to the other side, abandons the transfer
+            self.scheduler.cancel(Event::SerialTransfer);
+        }
+    }
+
+    /// Catches TIMA up before a timer register changes, then reschedules its overflow
+    fn write_timer(&mut self, write: impl FnOnce(&mut Timer)) {
+        if self.timer.sync(self.cycles) {
+            self.interrupts.request(InterruptSource::Timer);
         }
+        write(&mut self.timer);
+        self.schedule_timer_overflow();
     }
-    pub fn read_byte(&self, address: u16) -> u8 {
+
+    fn schedule_timer_overflow(&mut self) {
+        match self.timer.next_overflow() {
+            Some(at) => self.scheduler.schedule(Event::TimerOverflow, at),
+            None => self.scheduler.cancel(Event::TimerOverflow),
+        }
+    }
+
+    fn run_event(&mut self, event: Event, at: u64) {
+        match event {
+            Event::TimerOverflow => {
+                if self.timer.sync(at) {
+                    self.interrupts.request(InterruptSource::Timer);
+                }
+                self.schedule_timer_overflow();
+            }
+            Event::SerialTransfer => {
+                self.serial.finish_transfer();
+                self.interrupts.request(InterruptSource::Serial);
+            }
+            Event::PpuModeChange => {
+                match self.gpu.next_mode() {
+                    Interrupt::VBlank => {
+                        self.frames += 1;
+                        self.interrupts.request(InterruptSource::VBlank);
+                    }
+                    Interrupt::LCDStat => self.interrupts.request(InterruptSource::LCDStat),
+                    Interrupt::Both => {
+                        self.frames += 1;
+                        self.interrupts.request(InterruptSource::VBlank);
+                        self.interrupts.request(InterruptSource::LCDStat);
+                    }
+                    Interrupt::None => {}
+                }
+                self.ppu_mode_started = at;
+                self.scheduler.schedule(Event::PpuModeChange, at + self.gpu.mode_length() as u64);
+            }
+        }
+    }
+
+    pub fn press_key(&mut self, key: Key) {
+        if self.keypad.press(key) {
+            self.interrupts.request(InterruptSource::Joypad);
+            self.joypad_edge = true;
+        }
+    }
+
+    pub fn release_key(&mut self, key: Key) {
+        self.keypad.release(key);
+    }
+}
+
+impl Bus for MemoryBus {
+    fn has_boot_rom(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    fn power_on(&mut self) {
+        // RAM and the cartridge keep their contents, everything the boot ROM goes on to set up doesn't
+        self.gpu = GPU::new();
+        self.timer = Timer::new();
+        self.timer.sync(self.cycles);
+        self.timer.set_divider(self.cycles, 0);
+        self.dma = Dma::new();
+        self.keypad = Keypad::new();
+        self.serial = Serial::new();
+        self.io = [0; IO_SIZE];
+        self.interrupts = InterruptController::new();
+        self.scheduler = Scheduler::new();
+        self.boot_rom_mapped = self.boot_rom.is_some();
+        self.restart_ppu();
+    }
+
+    fn set_post_boot_state(&mut self, model: Model) {
+        self.keypad.write(0xCF);
+        self.serial.data = 0x00;
+        self.serial.control = if model.is_cgb() { 0x7F } else { 0x7E };
+        // Only the upper byte of the divider (DIV) is documented, the rest depends on boot timing
+        let divider = match model {
+            Model::DMG0 => 0x1800,
+            Model::DMG | Model::MGB => 0xAB00,
+            _ => 0x0000,
+        };
+        self.timer.set_divider(self.cycles, divider);
+        self.write_timer(|timer| {
+            timer.tima = 0x00;
+            timer.tma = 0x00;
+            timer.tac = 0xF8;
+        });
+        self.interrupts.write_flag(0xE1);
+        self.interrupts.enable = 0x00;
+
+        // Sound registers NR10-NR52, kept in io until there's an APU
+        let sound: [(usize, u8); 21] = [
+            (0x10, 0x80), (0x11, 0xBF), (0x12, 0xF3), (0x13, 0xFF), (0x14, 0xBF),
+            (0x16, 0x3F), (0x17, 0x00), (0x18, 0xFF), (0x19, 0xBF),
+            (0x1A, 0x7F), (0x1B, 0xFF), (0x1C, 0x9F), (0x1D, 0xFF), (0x1E, 0xBF),
+            (0x20, 0xFF), (0x21, 0x00), (0x22, 0x00), (0x23, 0xBF),
+            (0x24, 0x77), (0x25, 0xF3), (0x26, if model.is_sgb() { 0xF0 } else { 0xF1 }),
+        ];
+        for (offset, value) in sound {
+            self.io[offset] = value;
+        }
+
+        // The boot ROM hands over in VBlank, on line 153 where LY already reads 0 except on the DMG0
+        self.gpu.lcd.control.raw = 0x91;
+        self.gpu.modes = Modes::VBlank;
+        self.gpu.lcd.ly = if model == Model::DMG0 { 0x91 } else { 0x00 };
+        self.gpu.lcd.lyc = 0x00;
+        self.gpu.lyc_flag = self.gpu.lcd.ly == self.gpu.lcd.lyc;
+        self.restart_ppu();
+        self.gpu.lcd.status = 0x85;
+        self.gpu.lcd.scroll_y = 0x00;
+        self.gpu.lcd.scroll_x = 0x00;
+        self.dma.source = if model.is_cgb() { 0x00 } else { 0xFF };
+        self.gpu.lcd.bg_palette = 0xFC;
+        self.gpu.lcd.obj_palette_0 = 0xFF;
+        self.gpu.lcd.obj_palette_1 = 0xFF;
+        self.gpu.lcd.window_y = 0x00;
+        self.gpu.lcd.window_x = 0x00;
+    }
+
+    /// Runs the OAM DMA and whatever events have come due, latching any interrupts they raise into IF.
+    /// The CPU calls this every M-cycle, it doesn't run ahead to the next event
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        for _ in 0..cycles / 4 {
+            if let Some((source, index)) = self.dma.step() {
+                let data = self.read_byte(source);
+                self.gpu.write_oam(index, data);
+            }
+        }
+        while let Some((at, event)) = self.scheduler.pop_due(self.cycles) {
+            self.run_event(event, at);
+        }
+        self.devices.tick(cycles, &mut self.interrupts);
+    }
+    fn read_byte(&self, address: u16) -> u8 {
+        if let Some(device) = self.devices.get(address) {
+            return device.read(address);
+        }
         let addr = address as usize; // Convert once here
 
         match addr {
-            0xFF04 => (self.timer.divider >> 8) as u8,
-            0xFF05 => self.timer.tima,
+            0xFF00 => self.keypad.read(),
+            0xFF01 => self.serial.data,
+            0xFF02 => self.serial.read_control(),
+            0xFF04 => (self.timer.divider(self.cycles) >> 8) as u8,
+            0xFF05 => self.timer.tima(self.cycles),
             0xFF06 => self.timer.tma,
             0xFF07 => self.timer.tac,
-            // Cartridge ROM Banks
+            0xFF0F => self.interrupts.read_flag(),
+            // Cartridge ROM Banks, with the boot ROM laid over them until it's unmapped
+            0x0000..=0x08FF if self.boot_rom_mapped => {
+                self.read_boot_rom(address).unwrap_or_else(|| self.cartridge.read_rom(address))
+            }
             0x0000..=0x7FFF => self.cartridge.read_rom(address),
 
             // GPU VRAM
@@ -69,22 +379,26 @@ impl MemoryBus {
             WRAM_BEGIN..=WRAM_END => self.wram_bank[addr - WRAM_BEGIN],
 
             // Echo RAM (Mirror of WRAM - common in GB games)
-            0xE000..=0xFDFF => self.wram_bank[addr - 0xE000],
+            ECHO_BEGIN..=ECHO_END => self.wram_bank[addr - ECHO_BEGIN],
 
-            // GPU OAM (Object Attribute Memory)
+            // GPU OAM (Object Attribute Memory), the DMA owns it while a transfer is running
+            OAM_BEGIN..=OAM_END if self.dma.is_active() => 0xFF,
             OAM_BEGIN..=OAM_END => self.gpu.oam[addr - OAM_BEGIN],
 
             // GPU I/O Registers (Direct mapping)
             0xFF40 => self.gpu.lcd.control.raw,
+            0xFF41 => self.gpu.stat(),
             0xFF42 => self.gpu.lcd.scroll_y,
             0xFF43 => self.gpu.lcd.scroll_x,
-            0xFF44 => self.gpu.lcd.ly,
+            0xFF44 => self.fixed_ly.unwrap_or(self.gpu.lcd.ly),
             0xFF45 => self.gpu.lcd.lyc,
+            0xFF46 => self.dma.source,
             0xFF47 => self.gpu.lcd.bg_palette,
             0xFF48 => self.gpu.lcd.obj_palette_0,
             0xFF49 => self.gpu.lcd.obj_palette_1,
             0xFF4A => self.gpu.lcd.window_y,
             0xFF4B => self.gpu.lcd.window_x,
+            0xFF50 => 0xFF,
 
             // Other I/O and Timer
             IO_BEGIN..=IO_END => self.io[addr - IO_BEGIN],
@@ -93,21 +407,29 @@ impl MemoryBus {
             HRAM_BEGIN..=HRAM_END => self.hram[addr - HRAM_BEGIN],
 
             // Interrupt Enable Register
-            0xFFFF => self.interrupt_enable,
+            0xFFFF => self.interrupts.enable,
 
 
             _ => 0xFF,
         }
     }
 
-    pub fn write_byte(&mut self, address: u16, value: u8) {
+    fn write_byte(&mut self, address: u16, value: u8) {
+        if let Some(device) = self.devices.get_mut(address) {
+            device.write(address, value);
+            return;
+        }
         let addr = address as usize;
 
         match addr {
-            0xFF04 => self.timer.divider = 0, // Writing to DIV resets it to 0
-            0xFF05 => self.timer.tima = value,
-            0xFF06 => self.timer.tma = value,
-            0xFF07 => self.timer.tac = value,
+            0xFF00 => self.keypad.write(value),
+            0xFF01 => self.serial.data = value,
+            0xFF02 => self.write_serial_control(value),
+            0xFF04 => self.timer.set_divider(self.cycles, 0), // Writing to DIV resets it to 0
+            0xFF05 => self.write_timer(|timer| timer.tima = value),
+            0xFF06 => self.write_timer(|timer| timer.tma = value),
+            0xFF07 => self.write_timer(|timer| timer.tac = value),
+            0xFF0F => self.interrupts.write_flag(value),
             0x0000..=0x7FFF => self.cartridge.write_rom(address, value),
 
             VRAM_BEGIN..=VRAM_END => self.gpu.write_vram(addr - VRAM_BEGIN, value),
@@ -116,15 +438,21 @@ impl MemoryBus {
 
             WRAM_BEGIN..=WRAM_END => self.wram_bank[addr - WRAM_BEGIN] = value,
 
-            0xE000..=0xFDFF => self.wram_bank[addr - 0xE000] = value,
+            ECHO_BEGIN..=ECHO_END => self.wram_bank[addr - ECHO_BEGIN] = value,
 
+            OAM_BEGIN..=OAM_END if self.dma.is_active() => {}
             OAM_BEGIN..=OAM_END => self.gpu.write_oam(addr - OAM_BEGIN, value),
 
-            // DMA Transfer (Very important for sprites!)
-            0xFF46 => self.perform_dma(value),
+            // DMA Transfer (Very important for sprites!), copied a byte per machine cycle in tick
+            0xFF46 => self.dma.start(value),
 
             // GPU I/O Registers
-            0xFF40 => self.gpu.lcd.control.raw = value,
+            0xFF40 => self.write_lcd_control(value),
+            0xFF41 => {
+                // Only the interrupt select bits are writable, the mode and LY=LYC bits stay as they were
+                self.gpu.lcd.status = (self.gpu.lcd.status & 0b1000_0111) | (value & 0b0111_1000);
+                self.gpu.lyc_interrupt_bool = value & 0b0100_0000 != 0;
+            }
             0xFF42 => self.gpu.lcd.scroll_y = value,
             0xFF43 => self.gpu.lcd.scroll_x = value,
             0xFF45 => self.gpu.lcd.lyc = value,
@@ -133,18 +461,112 @@ impl MemoryBus {
             0xFF49 => self.gpu.lcd.obj_palette_1 = value,
             0xFF4A => self.gpu.lcd.window_y = value,
             0xFF4B => self.gpu.lcd.window_x = value,
+            // Any non-zero write unmaps the boot ROM, it can't be mapped back in without a reset
+            0xFF50 if value != 0 => self.boot_rom_mapped = false,
 
             IO_BEGIN..=IO_END => self.io[addr - IO_BEGIN] = value,
             HRAM_BEGIN..=HRAM_END => self.hram[addr - HRAM_BEGIN] = value,
-            0xFFFF => self.interrupt_enable = value,
+            0xFFFF => self.interrupts.enable = value,
             _ => {}
         }
     }
-    fn perform_dma(&mut self, value: u8) {
-        let base_address = (value as u16) << 8;
-        for i in 0..0xA0 { // OAM is 160 bytes
-            let data = self.read_byte(base_address + i);
-            self.gpu.write_oam(i as usize, data);
+
+    fn interrupts(&self) -> &InterruptController {
+        &self.interrupts
+    }
+
+    fn interrupts_mut(&mut self) -> &mut InterruptController {
+        &mut self.interrupts
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.cartridge.rom_bank
+    }
+
+    fn take_joypad_edge(&mut self) -> bool {
+        std::mem::take(&mut self.joypad_edge)
+    }
+
+    fn enter_stop(&mut self) {
+        self.joypad_edge = false;
+        self.timer.set_divider(self.cycles, 0);
+        self.gpu.blank_screen();
+    }
+
+    fn exit_stop(&mut self) {
+        self.timer.set_divider(self.cycles, 0);
+        self.gpu.unblank_screen();
+    }
+
+    fn trigger_oam_bug(&mut self, address: u16, kind: OamCorruption) {
+        if (0xFE00..=0xFEFF).contains(&address) {
+            self.gpu.corrupt_oam(kind, self.cycles - self.ppu_mode_started);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bus() -> MemoryBus {
+        MemoryBus::new(Cartridge::from_rom(vec![0; 0x8000]).unwrap())
+    }
+
+    #[test]
+    fn all_of_work_ram_is_backed() {
+        let mut bus = bus();
+        for address in [0xC000, 0xCFFF, 0xD000, 0xDFFF] {
+            bus.write_byte(address, 0x42);
+            assert_eq!(bus.read_byte(address), 0x42, "{:#06x}", address);
+        }
+    }
+
+    #[test]
+    fn echo_ram_mirrors_c000_to_ddff() {
+        let mut bus = bus();
+        bus.write_byte(0xD000, 0x12);
+        assert_eq!(bus.read_byte(0xF000), 0x12);
+        bus.write_byte(0xF000, 0x34);
+        assert_eq!(bus.read_byte(0xD000), 0x34);
+        bus.write_byte(0xFDFF, 0x56);
+        assert_eq!(bus.read_byte(0xDDFF), 0x56);
+        bus.write_byte(0xE000, 0x78);
+        assert_eq!(bus.read_byte(0xC000), 0x78);
+    }
+
+    fn run(bus: &mut MemoryBus, cycles: u64) {
+        for _ in 0..cycles / 4 {
+            bus.tick(4);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn turning_the_lcd_back_on_starts_a_new_frame() {
+        let mut bus = bus();
+        bus.write_byte(0xFF40, 0x91);
+        run(&mut bus, 20 * 456 + 100); // partway through line 20
+        bus.write_byte(0xFF40, 0x11);
+        run(&mut bus, 1000);
+        bus.write_byte(0xFF0F, 0);
+        bus.write_byte(0xFF40, 0x91);
+
+        // line 0 from the start of its OAM scan
+        assert_eq!(bus.read_byte(0xFF44), 0);
+        assert_eq!(bus.read_byte(0xFF41) & 0b11, 2);
+        run(&mut bus, 80);
+        assert_eq!(bus.read_byte(0xFF41) & 0b11, 3);
+        run(&mut bus, 456 - 80);
+        assert_eq!(bus.read_byte(0xFF44), 1);
+        assert_eq!(bus.read_byte(0xFF41) & 0b11, 2);
+
+        // and the frame runs all the way to its VBlank
+        run(&mut bus, 143 * 456 - 4);
+        assert_eq!(bus.read_byte(0xFF44), 143);
+        assert_eq!(bus.interrupts.flag & InterruptSource::VBlank.bit(), 0);
+        run(&mut bus, 4);
+        assert_eq!(bus.read_byte(0xFF44), 144);
+        assert_eq!(bus.read_byte(0xFF41) & 0b11, 1);
+        assert_ne!(bus.interrupts.flag & InterruptSource::VBlank.bit(), 0);
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::fmt;
+use crate::interrupts::InterruptSource;
+
+// Deeper than any sane game recurses, frames past this are dropped from the bottom
+const MAX_DEPTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt(InterruptSource),
+}
+
+/// One entry on the shadow call stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: FrameKind,
+    pub caller: u16, // address of the CALL/RST, or of the instruction an interrupt cut in before
+    pub target: u16,
+    pub bank: u16, // ROM bank mapped at `caller`, 0 outside the switchable area
+    pub sp: u16,   // where the return address was pushed to
+}
+
+impl CallFrame {
+    /// Whether `sp` has already moved up past this frame's return address. The stack can wrap
+    /// around 0000, so "up" means by less than half the address space rather than to a bigger number
+    fn is_below(&self, sp: u16) -> bool {
+        let distance = sp.wrapping_sub(self.sp);
+        distance != 0 && distance < 0x8000
+    }
+}
+
+impl fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            FrameKind::Call => "call".to_string(),
+            FrameKind::Rst => "rst".to_string(),
+            FrameKind::Interrupt(source) => format!("{:?} interrupt", source),
+        };
+        write!(f, "{} {:04x} from {:02x}:{:04x} (sp {:04x})", kind, self.target, self.bank, self.caller, self.sp)
+    }
+}
+
+/// Mirrors the return addresses the game pushes so a backtrace can be printed when something goes
+/// wrong. Games are free to move SP around themselves, so frames are matched up with returns by
+/// where their return address was pushed rather than by order alone
+pub struct CallStack {
+    frames: VecDeque<CallFrame>,
+    mismatches: u64,
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack { frames: VecDeque::new(), mismatches: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.mismatches = 0;
+    }
+
+    pub fn push(&mut self, frame: CallFrame) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    /// A RET or RETI about to pop its return address off `sp`
+    pub fn ret(&mut self, sp: u16) {
+        // frames below SP were abandoned, by resetting SP or popping their return address by hand
+        while self.frames.back().is_some_and(|frame| frame.is_below(sp)) {
+            self.frames.pop_back();
+            self.mismatches += 1;
+        }
+        match self.frames.back() {
+            Some(frame) if frame.sp == sp => {
+                self.frames.pop_back();
+            }
+            // returning through an address the game pushed itself
+            _ => self.mismatches += 1,
+        }
+    }
+
+    /// Innermost frame last
+    pub fn frames(&self) -> &VecDeque<CallFrame> {
+        &self.frames
+    }
+
+    /// How many returns didn't line up with the frame on top, a sign the game manipulated SP directly
+    pub fn mismatches(&self) -> u64 {
+        self.mismatches
+    }
+}
+
+impl fmt::Display for CallStack {
+    /// Innermost frame first, like a debugger's backtrace
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            writeln!(f, "#{} {}", depth, frame)?;
+        }
+        if self.mismatches > 0 {
+            writeln!(f, "({} returns didn't match a call)", self.mismatches)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(sp: u16) -> CallFrame {
+        CallFrame { kind: FrameKind::Call, caller: 0x0150, target: 0x0200, bank: 0, sp }
+    }
+
+    #[test]
+    fn returns_match_frames_across_the_stack_wrapping_around() {
+        let mut stack = CallStack::new();
+        stack.push(call(0x0000));
+        stack.push(call(0xFFFE));
+        // the inner frame's return address was popped by hand, the outer one returns normally
+        stack.ret(0x0000);
+        assert!(stack.frames().is_empty());
+        assert_eq!(stack.mismatches(), 1);
+    }
+
+    #[test]
+    fn frames_past_the_limit_drop_off_the_bottom() {
+        let mut stack = CallStack::new();
+        for depth in 0..=MAX_DEPTH as u16 {
+            stack.push(call(0xFFFE - depth * 2));
+        }
+        assert_eq!(stack.frames().len(), MAX_DEPTH);
+        assert_eq!(stack.frames()[0].sp, 0xFFFC);
+    }
+}
@@ -28,6 +28,15 @@ impl Cartridge {
         let mut rom = Vec::new();
         file.read_to_end(&mut rom).map_err(|e| e.to_string())?;
 
+        Cartridge::from_rom(rom)
+    }
+
+    /// Builds a cartridge around a ROM image that's already in memory
+    pub fn from_rom(rom: Vec<u8>) -> Result<Self, String> {
+        if rom.len() < 0x8000 {
+            return Err(format!("ROM too small: {} bytes", rom.len()));
+        }
+
         // 1. Parse Title (0x0134 - 0x0143)
         let title = String::from_utf8_lossy(&rom[0x0134..0x0143])
             .trim_matches(char::from(0))
@@ -50,8 +59,6 @@ impl Cartridge {
             _ => false,
         };
 
-        println!("Loaded ROM: {}, MBC: {:?}", title, mbc_type);
-
         Ok(Cartridge {
             rom,
             ram: vec![0; 0x8000], // Default 32KB RAM, can be resized based on 0x0148
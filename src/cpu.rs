@@ -1,17 +1,65 @@
 use std::collections::hash_map::Values;
+use std::fmt;
+use std::io::Write;
 use crate::instruction::{Instruction, ADDHLTarget, ArithmeticTarget, IncTarget, ByteAddressFromA, AFromByteAddress, IndirectFromA, AFromIndirect, WordByteSource, WordByteTarget, PrefixTarget, JumpTest, LoadType, LoadByteTarget, LoadByteSource, StackTarget};
-use crate::bus::MemoryBus;
+use crate::call_stack::{CallFrame, CallStack, FrameKind};
+use crate::bus::{Bus, MemoryBus};
+use crate::hooks::Hooks;
+use crate::interrupts::INTERRUPT_DISPATCH_CYCLES;
+use crate::GPU::gpu::OamCorruption;
+use crate::model::Model;
+use crate::opcodes::{self, Handler, Operation};
+mod state;
+#[cfg(test)]
+mod alu_tests;
+#[cfg(test)]
+mod tests;
+#[cfg(test)]
+mod single_step_tests;
+#[cfg(test)]
+mod dispatch_tests;
+#[cfg(test)]
+mod test_bus;
 const ZERO_FLAG_BYTE_POSITION: u8 = 7;
 const SUBTRACT_FLAG_BYTE_POSITION: u8 = 6;
 const HALF_CARRY_FLAG_BYTE_POSITION: u8 = 5;
 const CARRY_FLAG_BYTE_POSITION: u8 = 4;
-pub struct CPU {
+pub use state::CpuState;
+pub struct CPU<B: Bus = MemoryBus> {
+    model: Model,
     register: Register,
     pc: u16,
     sp:u16,
-    bus: MemoryBus,
+    bus: B,
     is_halted: bool,
+    halt_bug: bool,
+    is_stopped: bool,
+    is_locked_up: bool,
+    step_cycles: u8,
+    trace: Option<Box<dyn Write>>, // Gameboy Doctor log, one line before every instruction
+    hooks: Option<Box<Hooks>>,
+    call_stack: CallStack,
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    /// One of the unused opcodes ran and locked up the CPU until `restart`, `bank` is the ROM bank
+    /// mapped at `pc` (0 outside the switchable area)
+    IllegalInstruction { opcode: u8, pc: u16, bank: u16 },
+}
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::IllegalInstruction { opcode, pc, bank } => write!(
+                f,
+                "Illegal instruction 0x{:02x} at {:02x}:{:04x}, the CPU is locked up",
+                opcode,
+                bank,
+                pc
+            ),
+        }
+    }
+}
+impl std::error::Error for CpuError {}
 #[derive(Clone, Copy)]
 pub struct FlagsRegister {
     zero: bool,
@@ -29,1094 +77,796 @@ struct Register {
     h: u8,
     l: u8,
 }
-impl CPU {
-   pub fn step(&mut self) {
-        let mut instruction_byte = self.bus.read_byte(self.pc);
-        let prefixed = instruction_byte == 0xCb;
+impl<B: Bus> CPU<B> {
+    /// Creates a CPU that starts at 0x0100 as if the boot ROM of `model` had just finished,
+    /// or at 0x0000 when a boot ROM has been loaded into the bus so it can run for real
+    pub fn new(bus: B, model: Model) -> Self {
+        let mut cpu = CPU {
+            model,
+            register: Register::post_boot(model, 0),
+            pc: 0x0100,
+            sp: 0xFFFE,
+            bus,
+            is_halted: false,
+            halt_bug: false,
+            is_stopped: false,
+            is_locked_up: false,
+            step_cycles: 0,
+            trace: None,
+            hooks: None,
+            call_stack: CallStack::new(),
+        };
+        cpu.restart();
+        cpu
+    }
+    /// Returns the CPU and IO registers to their post-boot state, or to power on with the boot ROM
+    /// mapped when there is one, this is also the only way out of a lockup
+    pub fn restart(&mut self) {
+        self.is_halted = false;
+        self.halt_bug = false;
+        self.is_stopped = false;
+        self.is_locked_up = false;
+        self.call_stack.clear();
+        if self.bus.has_boot_rom() {
+            // the boot ROM sets up the registers itself
+            self.register = Register::power_on();
+            self.pc = 0x0000;
+            self.sp = 0x0000;
+            self.bus.power_on();
+            return;
+        }
+        let header_checksum = self.bus.read_byte(0x014D);
+        self.register = Register::post_boot(self.model, header_checksum);
+        self.pc = 0x0100;
+        self.sp = 0xFFFE;
+        self.bus.set_post_boot_state(self.model);
+    }
+   /// Runs a single instruction (or interrupt dispatch) and returns the number of T-cycles it took
+   pub fn step(&mut self) -> Result<u8, CpuError> {
+        if !self.hooks.as_ref().is_some_and(|hooks| hooks.wants_steps()) {
+            return self.run_step();
+        }
+        let state = self.state();
+        if let Some(hooks) = self.hooks.as_mut() {
+            hooks.before_step(&state);
+        }
+        let result = self.run_step();
+        if let Ok(cycles) = result {
+            let state = self.state();
+            if let Some(hooks) = self.hooks.as_mut() {
+                hooks.after_step(&state, cycles);
+            }
+        }
+        result
+    }
+    fn run_step(&mut self) -> Result<u8, CpuError> {
+        self.step_cycles = 0;
+        if self.is_locked_up {
+            // An illegal opcode hangs the CPU for good, only the rest of the machine keeps running
+            self.tick();
+            return Ok(self.step_cycles);
+        }
+        if self.is_stopped {
+            // Nothing runs until a selected joypad line goes low, time still passes for whoever is
+            // waiting on the clock
+            if !self.bus.take_joypad_edge() {
+                self.tick();
+                return Ok(self.step_cycles);
+            }
+            self.bus.exit_stop();
+            self.is_stopped = false;
+        }
+        if self.is_halted {
+            // HALT ends as soon as an enabled interrupt is requested, even with IME off
+            if self.bus.interrupts().pending() == 0 {
+                self.tick();
+                return Ok(self.step_cycles);
+            }
+            self.is_halted = false;
+        }
+        if self.handle_interrupts() {
+            return Ok(self.step_cycles);
+        }
+        if self.trace.is_some() {
+            self.write_trace();
+        }
+        let opcode_pc = self.pc;
+        let mut instruction_byte = self.read_cycle(self.pc);
+        let prefixed = instruction_byte == 0xCB;
         if prefixed {
-            instruction_byte = self.bus.read_byte(self.pc + 1);
+            // after the HALT bug PC hasn't moved past the prefix, so it's read again as the sub-opcode
+            let address = if self.halt_bug { self.pc } else { self.pc.wrapping_add(1) };
+            instruction_byte = self.read_cycle(address);
+        }
+        if self.halt_bug {
+            // PC fails to increment past the opcode, so the byte after HALT is read a second time
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
         }
-        let next_pc = if let Some(instruction) = Instruction::from_byte(instruction_byte,prefixed) {
-            self.execute(instruction)
+        let opcode = opcodes::lookup(instruction_byte, prefixed).expect("0xCB is always read as a prefix");
+        let expected_cycles = if cfg!(debug_assertions) && self.branch_taken(&opcode.instruction) {
+            opcode.cycles_taken
         } else {
-            let description = format!("0x{}{:x}", if prefixed { "cb" } else { "" }, instruction_byte);
-            panic!("Unkown instruction found for: {}", description)
+            opcode.cycles
         };
+        let next_pc = Self::HANDLERS[opcode.operation as usize](self, &opcode.instruction);
+        debug_assert_eq!(self.step_cycles, expected_cycles, "bus accesses don't add up at {:#06x}", self.pc);
         self.pc = next_pc;
+        if self.is_locked_up {
+            return Err(CpuError::IllegalInstruction {
+                opcode: instruction_byte,
+                pc: opcode_pc,
+                bank: self.rom_bank_at(opcode_pc),
+            });
+        }
+        self.bus.interrupts_mut().step();
+        Ok(self.step_cycles)
     }
-    fn execute(&mut self, instruction: Instruction) -> u16 {
-        if self.is_halted {return self.pc}
-        match instruction {
-            Instruction::NOP() => {
-                self.pc.wrapping_add(1)
-            }
-            Instruction::HALT() => {
-                self.is_halted = true;
-                self.pc.wrapping_add(1)
-            }
-            Instruction::LD(load_type) => {
-                match load_type {
-                    LoadType::Byte(target, source) => {
-                        let source_value = match source {
-                            LoadByteSource::A => self.register.a,
-                            LoadByteSource::D8 => self.bus.read_byte(self.pc+1),
-                            LoadByteSource::HLI => self.bus.read_byte(self.register.get_hl()),
-                            LoadByteSource::B => self.register.b,
-                            LoadByteSource::C => self.register.c,
-                            LoadByteSource::L => self.register.l,
-                            LoadByteSource::H => self.register.h,
-                            LoadByteSource::E => self.register.e,
-                            LoadByteSource::D => self.register.d,
-                        };
-                        match target {
-                            LoadByteTarget::A => self.register.a = source_value,
-                            LoadByteTarget::HLI => self.bus.write_byte(self.register.get_hl(), source_value),
-                            LoadByteTarget::B => self.register.b = source_value,
-                            LoadByteTarget::C => self.register.c = source_value,
-                            LoadByteTarget::L => self.register.l = source_value,
-                            LoadByteTarget::E => self.register.e = source_value,
-                            LoadByteTarget::D => self.register.d = source_value,
-                            LoadByteTarget::H => self.register.h = source_value,
-                        };
-                        match source {
-                            LoadByteSource::D8 => self.pc.wrapping_add(2),
-                            _ => self.pc.wrapping_add(1),
-                        }
-                    }
-                    LoadType::Word(target,source) => {
-                        let source_value = match source {
-                            WordByteSource::SP => self.sp,
-                            WordByteSource::U16 => {let low = self.bus.read_byte(self.pc.wrapping_add(1));
-                                let high = self.bus.read_byte(self.pc.wrapping_add(2));
-                              ((high as u16) << 8) | (low as u16)}
-                        };
-                        match target {
-                            WordByteTarget::U16 => {let low = self.bus.read_byte(self.pc.wrapping_add(1));
-                                let high = self.bus.read_byte(self.pc.wrapping_add(2));
-                                let addr = ((high as u16) << 8) | (low as u16);
-                                self.bus.write_byte(addr, (source_value & 0xFF) as u8);
-                                self.bus.write_byte(addr.wrapping_add(1), (source_value >> 8) as u8);}
-                            WordByteTarget::SP => self.sp = source_value,
-                            WordByteTarget::BC => self.register.set_bc(source_value),
-                            WordByteTarget::DE => self.register.set_de(source_value),
-                            WordByteTarget::HL => self.register.set_hl(source_value),
-                        }
-                        let add = match source {
-                            WordByteSource::U16 | WordByteSource::SP => 3,
-                            _=> 1
-                        };
-                        self.pc.wrapping_add(add)
-                    }
-                    LoadType::AFromIndirect(source) => {
-                        match source {
-                            AFromIndirect::BC => {
-                                let addr = self.register.get_bc();
-                                self.register.a = self.bus.read_byte(addr);
-                            }
-                            AFromIndirect::DE => {
-                                let addr = self.register.get_de();
-                                self.register.a = self.bus.read_byte(addr);
-                            }
-                            AFromIndirect::HLMinus => {
-                                let addr = self.register.get_hl();
-                                self.register.a = self.bus.read_byte(addr);
-                                self.register.set_hl(addr.wrapping_sub(1));
-                            }
-                            AFromIndirect::HLPlus => {
-                                let addr = self.register.get_hl();
-                                self.register.a = self.bus.read_byte(addr);
-                                self.register.set_hl(addr.wrapping_add(1));
-                            }
-                        }
-
-                        // increment the program counter and return it
-                        self.pc = self.pc.wrapping_add(1);
-                        self.pc
-                    }
-
-                    LoadType::IndirectFromA(indirect) => {
-                        match indirect {
-                            IndirectFromA::HLPlus => {
-                                let addr = self.register.get_hl();
-                                self.bus.write_byte(addr, self.register.a);
-                                self.register.set_hl(addr.wrapping_add(1));
-                            }
-                            IndirectFromA::HLMinus => {
-                                let addr = self.register.get_hl();
-                                self.bus.write_byte(addr, self.register.a);
-                                self.register.set_hl(addr.wrapping_sub(1));
-                            }
-                            IndirectFromA::BC => {
-                                let addr = self.register.get_bc();
-                                self.bus.write_byte(addr, self.register.a);
-                            }
-                            IndirectFromA::DE => {
-                                let addr = self.register.get_de();
-                                self.bus.write_byte(addr, self.register.a);
-                            }
-                        }
-                        self.pc.wrapping_add(1)
-                    }
-                    LoadType::AFromByteAddress(target) => {
-                        match target {
-                            AFromByteAddress::U16 => {
-                                let low = self.bus.read_byte(self.pc.wrapping_add(1));
-                                let high = self.bus.read_byte(self.pc.wrapping_add(2));
-                                let addr = ((high as u16) << 8) | (low as u16);
-                                self.register.a = self.bus.read_byte(addr);
-                                self.pc.wrapping_add(3) // opcode + 16-bit address
-                            }
-                            AFromByteAddress::FF00U8 => {
-                                let offset = self.bus.read_byte(self.pc.wrapping_add(1));
-                                let addr = 0xFF00u16 + offset as u16;
-                                self.register.a = self.bus.read_byte(addr);
-                                self.pc.wrapping_add(2) // opcode + 8-bit offset
-                            }
-                            AFromByteAddress::FFOOC => {
-                                let addr = 0xFF00u16 + self.register.c as u16;
-                                self.register.a = self.bus.read_byte(addr);
-                                self.pc.wrapping_add(1) // only opcode, no extra byte
-                            }
-                        }
-                    }
-                    LoadType::ByteAddressFromA(store_type) => {
-                        match store_type {
-                            ByteAddressFromA::U16 => {
-                                let low = self.bus.read_byte(self.pc.wrapping_add(1));
-                                let high = self.bus.read_byte(self.pc.wrapping_add(2));
-                                let addr = ((high as u16) << 8) | (low as u16);
-                                self.bus.write_byte(addr, self.register.a);
-                                self.pc.wrapping_add(3)
-                            }
-                            ByteAddressFromA::FF00U8 => {
-                                let offset = self.bus.read_byte(self.pc.wrapping_add(1));
-                                let addr = 0xFF00u16 + offset as u16;
-                                self.bus.write_byte(addr, self.register.a);
-                                self.pc.wrapping_add(2)
-                            }
-                            ByteAddressFromA::FFOOC => {
-                                let addr = 0xFF00u16 + self.register.c as u16;
-                                self.bus.write_byte(addr, self.register.a);
-                                self.pc.wrapping_add(1)
-                            }
-                        }
-                    }
-                }
-            }
-            Instruction::JP(test) => {
-                let jump_condition = match test {
-                    JumpTest::NotZero => !self.register.f.zero,
-                    JumpTest::NotCarry => !self.register.f.carry,
-                    JumpTest::Zero => self.register.f.zero,
-                    JumpTest::Carry => self.register.f.carry,
-                    JumpTest::Always => true
-                };
-                self.jump(jump_condition)
-            }
-            Instruction::SWAP(target) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.register.a = self.swap(self.register.a);
-                        self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.swap(self.register.b);
-                        self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.swap(self.register.c);
-                        self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.swap(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.swap(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.swap(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.swap(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.swap(value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-
-                }
-
-            }
-            Instruction::SLA(target) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.register.a = self.sla(self.register.a);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.sla(self.register.b);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.sla(self.register.c);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.sla(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.sla(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.sla(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.sla(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.sla(value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-                }
-
-            }
-            Instruction::SRA(target) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.register.a = self.sra(self.register.a);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.sra(self.register.b);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.sra(self.register.c);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.sra(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.sra(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.sra(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.sra(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.sra(value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-                }
-
-            }
-            Instruction::RLC(target) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.register.a = self.rlc(self.register.a);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.rlc(self.register.b);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.rlc(self.register.c);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.rlc(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.rlc(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.rlc(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.rlc(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.rlc(value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-                }
-
-            }
-            Instruction::RRC(target) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.register.a = self.rrc(self.register.a);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.rrc(self.register.b);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.rrc(self.register.c);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.rrc(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.rrc(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.rrc(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.rrc(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.rrc(value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-                }
-
-            }
-            Instruction::RL(target) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.register.a = self.rl(self.register.a);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.rl(self.register.b);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.rl(self.register.c);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.rl(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.rl(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.rl(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.rl(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.rl(value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-
-                }
-            }
-            Instruction::RR(target) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.register.a = self.rr(self.register.a);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.rr(self.register.b);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.rr(self.register.c);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.rr(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.rr(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.rr(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.rr(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.rr(value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-                }
-            }
-            Instruction::SRL(target) => {
-                match target {
-                    PrefixTarget::A => {
-                         self.register.a = self.srl(self.register.a);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.srl(self.register.b);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.srl(self.register.c);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.srl(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.srl(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.srl(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.srl(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.srl(value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-                }
-            }
-            Instruction::SET(target,bit) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.register.a = self.set(bit,self.register.a);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.set(bit,self.register.b);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.set(bit,self.register.c);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.set(bit,self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.set(bit,self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.set(bit,self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.set(bit,self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.set(bit, value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-
-                }
-            }
-            Instruction::BIT(target,bit) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.bit(self.register.a,bit);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.bit(self.register.b,bit);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.bit(self.register.c,bit);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.bit(self.register.d,bit);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.bit(self.register.e,bit);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.bit(self.register.h,bit);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.bit(self.register.l,bit);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let value = self.register.get_hl();
-                        let new = self.bus.read_byte(value);
-                        self.bit(bit,new);
-                        self.pc.wrapping_add(2)
-                    }
-                }
-            }
-            Instruction::RESET(target,bit) => {
-                match target {
-                    PrefixTarget::A => {
-                        self.register.a = self.reset(bit,self.register.a);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::B => {
-                        self.register.b = self.reset(bit,self.register.b);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::C => {
-                        self.register.c = self.reset(bit,self.register.c);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::D => {
-                        self.register.d = self.reset(bit,self.register.d);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::E => {
-                        self.register.e = self.reset(bit,self.register.e);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::H => {
-                        self.register.h = self.reset(bit,self.register.h);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::L => {
-                        self.register.l = self.reset(bit,self.register.l);self.pc.wrapping_add(1)
-                    }
-                    PrefixTarget::HL => {
-                        let addr = self.register.get_hl();
-                        let value = self.bus.read_byte(addr);   // load from memory
-                        let new_value = self.reset(bit, value);    // clear bit in that byte
-                        self.bus.write_byte(addr, new_value);self.pc.wrapping_add(1)
-                    }
-
-                }
-            }
-            Instruction::RRA() => {
-                self.rra();self.pc.wrapping_add(1)
-            }
-            Instruction::SCF() => { self.scf();self.pc.wrapping_add(1)
-            }
-            Instruction:: CCF() => {
-                self.ccf();self.pc.wrapping_add(1)
-            }
-            Instruction::CPL() => {
-                    self.cpl();self.pc.wrapping_add(1)
-                }
-            Instruction::DEC(target) => {
-                match target {
-                    IncTarget::A => {
-                        self.register.a = self.dec8(self.register.a);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::B => {
-                        self.register.b = self.dec8(self.register.b);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::C => {
-                        self.register.c = self.dec8(self.register.c);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::D => {
-                        self.register.d = self.dec8(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::E => {
-                        self.register.e = self.dec8(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::H => {
-                        self.register.h = self.dec8(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::L => {
-                        self.register.l = self.dec8(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::BC => {
-                        let copy = self.dec16(self.register.get_bc());
-                        self.register.set_bc(copy);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::HL => {
-                        let copy = self.dec16(self.register.get_hl());
-                        self.register.set_hl(copy);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::DE => {
-                        let copy = self.dec16(self.register.get_de());
-                        self.register.set_de(copy);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::SP => {
-                        let copy = self.dec16(self.sp);
-                        self.sp = copy;self.pc.wrapping_add(1)
-                    }
-                }
-            }
-            Instruction::INC(target) => {
-                match target {
-                    IncTarget::A => {
-                        self.register.a = self.inc8(self.register.a);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::B => {
-                        self.register.b = self.inc8(self.register.b);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::C => {
-                        self.register.c = self.inc8(self.register.c);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::D => {
-                        self.register.d = self.inc8(self.register.d);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::E => {
-                        self.register.e = self.inc8(self.register.e);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::H => {
-                        self.register.h = self.inc8(self.register.h);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::L => {
-                        self.register.l = self.inc8(self.register.l);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::BC => {
-                        let copy = self.inc16(self.register.get_bc());
-                        self.register.set_bc(copy);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::HL => {
-                        let copy = self.inc16(self.register.get_hl());
-                        self.register.set_hl(copy);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::DE => {
-                        let copy = self.inc16(self.register.get_de());
-                        self.register.set_de(copy);self.pc.wrapping_add(1)
-                    }
-                    IncTarget::SP => {
-                        // TODO this looks wrong
-                        self.sp = self.sp.wrapping_add(1);
-                        self.pc.wrapping_add(1)
-                    }
-                }
-            }
-
-            Instruction::OR(target) => {
-                match target {
-                    ArithmeticTarget::PC => {
-                        let value = self.bus.read_byte(self.pc+1);
-                        let new_value = self.or(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::HL => {
-                        let value = self.bus.read_byte(self.register.get_hl());
-                        let new_value = self.or(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::A => {
-                        let value = self.register.a;
-                        let new_value = self.or(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.register.b;
-                        let new_value = self.or(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.register.c;
-                        let new_value = self.or(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.register.d;
-                        let new_value = self.or(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.register.e;
-                        let new_value = self.or(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.register.h;
-                        let new_value = self.or(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.register.l;
-                        let new_value = self.or(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                }
-
-            }
-            Instruction::XOR(target) => {
-                match target {
-                    ArithmeticTarget::PC => {
-                        let value = self.bus.read_byte(self.pc+1);
-                        let new_value = self.xor(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::HL => {
-                        let value = self.bus.read_byte(self.register.get_hl());
-                        self.register.a = self.xor(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::A => {
-                        let value = self.register.a;
-                        self.register.a = self.xor(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.register.b;
-                        self.register.a = self.xor(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.register.c;
-                        self.register.a = self.xor(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.register.d;
-                        self.register.a = self.xor(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.register.e;
-                        self.register.a = self.xor(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.register.h;
-                        self.register.a = self.xor(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.register.l;
-                        self.register.a = self.xor(value);
-                        self.pc.wrapping_add(1)
-                    }
-                }
-            }
-            Instruction::AND(target) => {
-                match target {
-                    ArithmeticTarget::PC => {
-                        let value = self.bus.read_byte(self.pc+1);
-                        let new_value = self.and(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::HL => {
-                        let value = self.bus.read_byte(self.register.get_hl());
-                        let new_value = self.or(value);
-                        self.register.a = value;
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::A => {
-                        let value = self.register.a;
-                        let new_value = self.and(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.register.b;
-                        let new_value = self.and(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.register.c;
-                        let new_value = self.and(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.register.d;
-                        let new_value = self.and(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.register.e;
-                        let new_value = self.and(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.register.h;
-                        let new_value = self.and(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.register.l;
-                        let new_value = self.and(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                }
+    /// Starts logging the CPU state before every instruction in the format Gameboy Doctor
+    /// (https://github.com/robert/gameboy-doctor) compares against, None stops it again.
+    /// The reference logs are taken with LY always reading 0x90, see `MemoryBus::fixed_ly`
+    pub fn set_trace(&mut self, sink: Option<Box<dyn Write>>) {
+        self.trace = sink;
+    }
+    /// Installs callbacks for tools to observe the CPU with, None removes them again
+    pub fn set_hooks(&mut self, hooks: Option<Hooks>) {
+        self.hooks = hooks.map(Box::new);
+    }
+    /// A:00 F:11 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:9999 PCMEM:AA,BB,CC,DD
+    fn write_trace(&mut self) {
+        let pcmem: Vec<u8> = (0..4).map(|offset| self.bus.read_byte(self.pc.wrapping_add(offset))).collect();
+        let line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            self.register.a,
+            u8::from(self.register.f),
+            self.register.b,
+            self.register.c,
+            self.register.d,
+            self.register.e,
+            self.register.h,
+            self.register.l,
+            self.sp,
+            self.pc,
+            pcmem[0],
+            pcmem[1],
+            pcmem[2],
+            pcmem[3]
+        );
+        if let Some(sink) = self.trace.as_mut() {
+            // a sink that can't be written to any more isn't worth failing the emulation over
+            if writeln!(sink, "{}", line).is_err() {
+                self.trace = None;
             }
-            Instruction::ADC(target) => {
-                match target {
-                    ArithmeticTarget::PC => {
-                        let value = self.bus.read_byte(self.pc+1);
-                        let new_value = self.adc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::HL => {
-                        let value = self.bus.read_byte(self.register.get_hl());
-                        let new_value = self.adc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::A => {
-                        let value = self.register.a;
-                        let new_value = self.adc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.register.b;
-                        let new_value = self.adc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.register.c;
-                        let new_value = self.adc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.register.d;
-                        let new_value = self.adc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.register.e;
-                        let new_value = self.adc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.register.h;
-                        let new_value = self.adc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.register.l;
-                        let new_value = self.adc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                }
+        }
+    }
+    /// The handler for each kind of instruction, in `Operation` order
+    const HANDLERS: [Handler<B>; Operation::COUNT] = [
+        Self::execute_nop,
+        Self::execute_halt,
+        Self::execute_stop,
+        Self::execute_illegal,
+        Self::execute_di,
+        Self::execute_ei,
+        Self::execute_load_byte,
+        Self::execute_load_word,
+        Self::execute_load_a_from_indirect,
+        Self::execute_load_indirect_from_a,
+        Self::execute_load_a_from_address,
+        Self::execute_load_address_from_a,
+        Self::execute_pop,
+        Self::execute_push,
+        Self::execute_load_hl_sp_offset,
+        Self::execute_load_sp_hl,
+        Self::execute_jp,
+        Self::execute_jp_hl,
+        Self::execute_jr,
+        Self::execute_call,
+        Self::execute_ret,
+        Self::execute_rst,
+        Self::execute_reti,
+        Self::execute_bit,
+        Self::execute_res,
+        Self::execute_set,
+        Self::execute_srl,
+        Self::execute_rr,
+        Self::execute_rl,
+        Self::execute_rrc,
+        Self::execute_rlc,
+        Self::execute_sra,
+        Self::execute_sla,
+        Self::execute_swap,
+        Self::execute_add,
+        Self::execute_adc,
+        Self::execute_sub,
+        Self::execute_sbc,
+        Self::execute_and,
+        Self::execute_or,
+        Self::execute_xor,
+        Self::execute_cp,
+        Self::execute_inc,
+        Self::execute_dec,
+        Self::execute_add_hl,
+        Self::execute_add_sp,
+        Self::execute_rra,
+        Self::execute_rla,
+        Self::execute_rrca,
+        Self::execute_rlca,
+        Self::execute_ccf,
+        Self::execute_scf,
+        Self::execute_cpl,
+        Self::execute_daa,
+    ];
+    fn branch_taken(&self, instruction: &Instruction) -> bool {
+        match instruction {
+            Instruction::JP(test) | Instruction::JR(test) | Instruction::CALL(test) | Instruction::RET(test) => {
+                self.jump_condition(test)
             }
-            Instruction::SBC(target) => {
-                match target {
-                    ArithmeticTarget::PC => {
-                        let value = self.bus.read_byte(self.pc+1);
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::HL => {
-                        let value = self.bus.read_byte(self.register.get_hl());
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::A => {
-                        let value = self.register.a;
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.register.b;
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.register.c;
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.register.d;
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.register.e;
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.register.h;
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.register.l;
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                }
+            _ => false,
+        }
+    }
+    pub fn is_locked_up(&self) -> bool {
+        self.is_locked_up
+    }
+    pub fn state(&self) -> CpuState {
+        let r = &self.register;
+        let mut state = CpuState::default();
+        state.set_a(r.a);
+        state.set_f(u8::from(r.f));
+        state.set_b(r.b);
+        state.set_c(r.c);
+        state.set_d(r.d);
+        state.set_e(r.e);
+        state.set_h(r.h);
+        state.set_l(r.l);
+        state.set_sp(self.sp);
+        state.set_pc(self.pc);
+        state.set_ime(self.bus.interrupts().ime);
+        state.set_halted(self.is_halted);
+        state
+    }
+    /// Replaces every register at once, an EI that hasn't taken effect yet is dropped
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.register = Register {
+            a: state.a(),
+            b: state.b(),
+            c: state.c(),
+            d: state.d(),
+            e: state.e(),
+            f: FlagsRegister::from(state.f()),
+            h: state.h(),
+            l: state.l(),
+        };
+        self.sp = state.sp();
+        self.pc = state.pc();
+        if state.ime() {
+            self.bus.interrupts_mut().enable();
+        } else {
+            self.bus.interrupts_mut().disable();
+        }
+        self.is_halted = state.halted();
+    }
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+    /// The shadow call stack, for printing a backtrace when something goes wrong
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+    /// Records a call from the current instruction, right after its return address was pushed
+    fn push_frame(&mut self, kind: FrameKind, target: u16) {
+        let frame = CallFrame { kind, caller: self.pc, target, bank: self.rom_bank_at(self.pc), sp: self.sp };
+        self.call_stack.push(frame);
+    }
+    fn rom_bank_at(&self, address: u16) -> u16 {
+        match address {
+            0x4000..=0x7FFF => self.bus.rom_bank(),
+            _ => 0,
+        }
+    }
+    /// Jumps to the highest priority pending interrupt if IME is set
+    fn handle_interrupts(&mut self) -> bool {
+        if !self.bus.interrupts().ime {
+            return false;
+        }
+        let Some(source) = self.bus.interrupts().highest_priority() else {
+            return false;
+        };
+        self.bus.interrupts_mut().acknowledge(source);
+        if let Some(hooks) = self.hooks.as_mut() {
+            hooks.interrupt(source);
+        }
+        // two wait states, then the push and the jump
+        self.tick();
+        self.tick();
+        self.push(self.pc);
+        self.push_frame(FrameKind::Interrupt(source), source.vector());
+        self.pc = source.vector();
+        debug_assert_eq!(self.step_cycles, INTERRUPT_DISPATCH_CYCLES);
+        true
+    }
+    /// Advances the rest of the machine by one machine cycle
+    fn tick(&mut self) {
+        self.bus.tick(4);
+        self.step_cycles += 4;
+    }
+    /// Every memory access takes one machine cycle, the rest of the machine runs before it happens
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        self.read_cycle_with(address, OamCorruption::Read)
+    }
+    /// A read that garbles OAM as `oam_bug`, which depends on what else happens in the same cycle
+    fn read_cycle_with(&mut self, address: u16, oam_bug: OamCorruption) -> u8 {
+        self.tick();
+        let value = self.bus.read_byte(address);
+        self.oam_bug(address, oam_bug);
+        if let Some(hooks) = self.hooks.as_mut() {
+            hooks.read(address, value);
+        }
+        value
+    }
+    fn write_cycle(&mut self, address: u16, value: u8) {
+        self.tick();
+        let Some(hooks) = self.hooks.as_mut() else {
+            self.bus.write_byte(address, value);
+            return;
+        };
+        hooks.write(address, value);
+        // writes to the ROM area go to the MBC, which may switch banks
+        let bank = self.bus.rom_bank();
+        self.bus.write_byte(address, value);
+        let new_bank = self.bus.rom_bank();
+        if address < 0x8000
+            && new_bank != bank
+            && let Some(hooks) = self.hooks.as_mut()
+        {
+            hooks.bank_switch(bank, new_bank);
+        }
+    }
+    /// Immediate byte `index` of the current instruction
+    fn read_operand(&mut self, index: u16) -> u8 {
+        self.read_cycle(self.pc.wrapping_add(1 + index))
+    }
+    fn execute_nop(&mut self, _instruction: &Instruction) -> u16 {
+        self.pc.wrapping_add(1)
+    }
+    fn execute_halt(&mut self, _instruction: &Instruction) -> u16 {
+        if !self.bus.interrupts().ime && self.bus.interrupts().pending() != 0 {
+            // HALT bug: the CPU doesn't halt and the next opcode gets fetched twice
+            self.halt_bug = true;
+        } else {
+            self.is_halted = true;
+        }
+        self.pc.wrapping_add(1)
+    }
+    fn execute_stop(&mut self, _instruction: &Instruction) -> u16 {
+        // STOP is followed by a padding byte, and entering it resets DIV
+        self.bus.enter_stop();
+        self.is_stopped = true;
+        self.pc.wrapping_add(2)
+    }
+    fn execute_illegal(&mut self, _instruction: &Instruction) -> u16 {
+        self.is_locked_up = true;
+        self.pc
+    }
+    fn execute_di(&mut self, _instruction: &Instruction) -> u16 {
+        self.bus.interrupts_mut().disable();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_ei(&mut self, _instruction: &Instruction) -> u16 {
+        self.bus.interrupts_mut().schedule_enable();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_load_byte(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::LD(LoadType::Byte(target, source)) = instruction else { unreachable!() };
+        let value = match source {
+            LoadByteSource::A => self.register.a,
+            LoadByteSource::B => self.register.b,
+            LoadByteSource::C => self.register.c,
+            LoadByteSource::D => self.register.d,
+            LoadByteSource::E => self.register.e,
+            LoadByteSource::H => self.register.h,
+            LoadByteSource::L => self.register.l,
+            LoadByteSource::HLI => self.read_cycle(self.register.get_hl()),
+            LoadByteSource::D8 => self.read_operand(0),
+        };
+        match target {
+            LoadByteTarget::A => self.register.a = value,
+            LoadByteTarget::B => self.register.b = value,
+            LoadByteTarget::C => self.register.c = value,
+            LoadByteTarget::D => self.register.d = value,
+            LoadByteTarget::E => self.register.e = value,
+            LoadByteTarget::H => self.register.h = value,
+            LoadByteTarget::L => self.register.l = value,
+            LoadByteTarget::HLI => self.write_cycle(self.register.get_hl(), value),
+        }
+        match source {
+            LoadByteSource::D8 => self.pc.wrapping_add(2),
+            _ => self.pc.wrapping_add(1),
+        }
+    }
+    fn execute_load_word(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::LD(LoadType::Word(target, source)) = instruction else { unreachable!() };
+        let value = match source {
+            WordByteSource::SP => self.sp,
+            WordByteSource::U16 => self.read_operand_word(),
+        };
+        match target {
+            WordByteTarget::U16 => {
+                let address = self.read_operand_word();
+                self.write_cycle(address, (value & 0xFF) as u8);
+                self.write_cycle(address.wrapping_add(1), (value >> 8) as u8);
             }
-            Instruction::SUB(target) => {
-                match target {
-                    ArithmeticTarget::PC => {
-                        let value = self.bus.read_byte(self.pc+1);
-                        let new_value = self.sub(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::HL => {
-                        let value = self.bus.read_byte(self.register.get_bc());
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::A => {
-                        let value = self.register.a;
-                        let new_value = self.sbc(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.register.b;
-                        let new_value = self.sub(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.register.c;
-                        let new_value = self.sub(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.register.d;
-                        let new_value = self.sub(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.register.e;
-                        let new_value = self.sub(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.register.h;
-                        let new_value = self.sub(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.register.l;
-                        let new_value = self.sub(value);
-                        self.register.a = new_value;self.pc.wrapping_add(1)
-                    }
-                }
+            WordByteTarget::SP => self.sp = value,
+            WordByteTarget::BC => self.register.set_bc(value),
+            WordByteTarget::DE => self.register.set_de(value),
+            WordByteTarget::HL => self.register.set_hl(value),
+        }
+        self.pc.wrapping_add(3)
+    }
+    fn execute_load_a_from_indirect(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::LD(LoadType::AFromIndirect(source)) = instruction else { unreachable!() };
+        match source {
+            AFromIndirect::BC => self.register.a = self.read_cycle(self.register.get_bc()),
+            AFromIndirect::DE => self.register.a = self.read_cycle(self.register.get_de()),
+            AFromIndirect::HLPlus | AFromIndirect::HLMinus => {
+                let address = self.register.get_hl();
+                self.register.a = self.read_cycle_with(address, OamCorruption::ReadWithIncrement);
+                let step = if matches!(source, AFromIndirect::HLPlus) { 1 } else { u16::MAX };
+                self.register.set_hl(address.wrapping_add(step));
             }
-            Instruction::ADD(target) => match target {
-                ArithmeticTarget::PC => {
-                    let value = self.bus.read_byte(self.pc+1);
-                    let new_value = self.add(value);
-                    self.register.a = new_value;self.pc.wrapping_add(1)
-                }
-                ArithmeticTarget::HL => {
-                    self.register.a = self.add(self.bus.read_byte(self.register.get_hl()));
-                    self.pc.wrapping_add(1)
-                }
-                ArithmeticTarget::A=> {
-                    let value = self.register.a;
-                    let new_value = self.add(value);
-                    self.register.a = new_value;self.pc.wrapping_add(1)
-                }
-                ArithmeticTarget::B => {
-                    let value = self.register.b;
-                    let new_value = self.add(value);
-                    self.register.a = new_value;self.pc.wrapping_add(1)
-                }
-                ArithmeticTarget::C => {
-                    let value = self.register.c;
-                    let new_value = self.add(value);
-                    self.register.a = new_value;self.pc.wrapping_add(1)
-                }
-                ArithmeticTarget::D => {
-                    let value = self.register.d;
-                    let new_value = self.add(value);
-                    self.register.a = new_value;self.pc.wrapping_add(1)
-                }
-                ArithmeticTarget::E => {
-                    let value = self.register.e;
-                    let new_value = self.add(value);
-                    self.register.a = new_value;self.pc.wrapping_add(1)
-                }
-                ArithmeticTarget::H => {
-                    let value = self.register.h;
-                    let new_value = self.add(value);
-                    self.register.a = new_value;self.pc.wrapping_add(1)
-                }
-                ArithmeticTarget::L => {
-                    let value = self.register.l;
-                    let new_value = self.add(value);
-                    self.register.a = new_value;self.pc.wrapping_add(1)
-                }
-
-            },
-            Instruction::CP(target) => {
-                match target {
-                    ArithmeticTarget::PC => {
-                        let value = self.bus.read_byte(self.pc+1);
-                        let new_value = self.sub(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::HL => {
-                        let value = self.bus.read_byte(self.register.get_hl());
-                        self.sub(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::A => {
-                        let value = self.register.a;
-                        let new_value = self.sub(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.register.b;
-                        let new_value = self.sub(value);
-                        self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.register.c;
-                        let new_value = self.sub(value);self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.register.d;
-                        let new_value = self.sub(value);self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.register.e;
-                        let new_value = self.sub(value);self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.register.h;
-                        let new_value = self.sub(value);self.pc.wrapping_add(1)
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.register.l;
-                        let new_value = self.sub(value);self.pc.wrapping_add(1)
-                    }
-                }
+        }
+        self.pc.wrapping_add(1)
+    }
+    fn execute_load_indirect_from_a(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::LD(LoadType::IndirectFromA(target)) = instruction else { unreachable!() };
+        match target {
+            IndirectFromA::BC => self.write_cycle(self.register.get_bc(), self.register.a),
+            IndirectFromA::DE => self.write_cycle(self.register.get_de(), self.register.a),
+            // the write and the increment only garble OAM once between them
+            IndirectFromA::HLPlus | IndirectFromA::HLMinus => {
+                let address = self.register.get_hl();
+                self.write_cycle(address, self.register.a);
+                self.oam_bug(address, OamCorruption::Write);
+                let step = if matches!(target, IndirectFromA::HLPlus) { 1 } else { u16::MAX };
+                self.register.set_hl(address.wrapping_add(step));
             }
-            Instruction::POP(target) => {
-                let result = self.pop();
-                match target {
-                    StackTarget::BC => self.register.set_bc(result),
-                    StackTarget::DE => self.register.set_de(result),
-                    StackTarget::Hl => self.register.set_hl(result),
-                    StackTarget::AF => self.register.set_af(result),
-
-                };
-                self.pc.wrapping_add(1)
+        }
+        self.pc.wrapping_add(1)
+    }
+    fn execute_load_a_from_address(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::LD(LoadType::AFromByteAddress(source)) = instruction else { unreachable!() };
+        let (address, length) = match source {
+            AFromByteAddress::U16 => (self.read_operand_word(), 3),
+            AFromByteAddress::FF00U8 => (0xFF00 | self.read_operand(0) as u16, 2),
+            AFromByteAddress::FFOOC => (0xFF00 | self.register.c as u16, 1),
+        };
+        self.register.a = self.read_cycle(address);
+        self.pc.wrapping_add(length)
+    }
+    fn execute_load_address_from_a(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::LD(LoadType::ByteAddressFromA(target)) = instruction else { unreachable!() };
+        let (address, length) = match target {
+            ByteAddressFromA::U16 => (self.read_operand_word(), 3),
+            ByteAddressFromA::FF00U8 => (0xFF00 | self.read_operand(0) as u16, 2),
+            ByteAddressFromA::FFOOC => (0xFF00 | self.register.c as u16, 1),
+        };
+        self.write_cycle(address, self.register.a);
+        self.pc.wrapping_add(length)
+    }
+    fn execute_pop(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::POP(target) = instruction else { unreachable!() };
+        let value = self.pop();
+        match target {
+            StackTarget::BC => self.register.set_bc(value),
+            StackTarget::DE => self.register.set_de(value),
+            StackTarget::Hl => self.register.set_hl(value),
+            StackTarget::AF => self.register.set_af(value),
+        }
+        self.pc.wrapping_add(1)
+    }
+    fn execute_push(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::PUSH(target) = instruction else { unreachable!() };
+        let value = match target {
+            StackTarget::BC => self.register.get_bc(),
+            StackTarget::DE => self.register.get_de(),
+            StackTarget::Hl => self.register.get_hl(),
+            StackTarget::AF => self.register.get_af(),
+        };
+        self.push(value);
+        self.pc.wrapping_add(1)
+    }
+    fn execute_load_hl_sp_offset(&mut self, _instruction: &Instruction) -> u16 {
+        let result = self.sp_plus_offset();
+        self.tick();
+        self.register.set_hl(result);
+        self.pc.wrapping_add(2)
+    }
+    fn execute_load_sp_hl(&mut self, _instruction: &Instruction) -> u16 {
+        self.tick();
+        self.sp = self.register.get_hl();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_jp(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::JP(test) = instruction else { unreachable!() };
+        let jump_condition = self.jump_condition(test);
+        self.jump(jump_condition)
+    }
+    fn execute_jp_hl(&mut self, _instruction: &Instruction) -> u16 {
+        self.register.get_hl()
+    }
+    fn execute_jr(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::JR(test) = instruction else { unreachable!() };
+        let jump_condition = self.jump_condition(test);
+        self.jump_relative(jump_condition)
+    }
+    fn execute_call(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::CALL(test) = instruction else { unreachable!() };
+        let jump_condition = self.jump_condition(test);
+        self.call(jump_condition)
+    }
+    fn execute_ret(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::RET(test) = instruction else { unreachable!() };
+        let jump_condition = self.jump_condition(test);
+        if !matches!(test, JumpTest::Always) {
+            // checking the condition costs an extra cycle
+            self.tick();
+        }
+        self.ret(jump_condition)
+    }
+    fn execute_rst(&mut self, instruction: &Instruction) -> u16 {
+        let &Instruction::RST(vector) = instruction else { unreachable!() };
+        self.push(self.pc.wrapping_add(1));
+        self.push_frame(FrameKind::Rst, vector as u16);
+        vector as u16
+    }
+    fn execute_reti(&mut self, _instruction: &Instruction) -> u16 {
+        self.bus.interrupts_mut().enable();
+        self.ret(true)
+    }
+    fn execute_bit(&mut self, instruction: &Instruction) -> u16 {
+        let &Instruction::BIT(ref target, bit) = instruction else { unreachable!() };
+        let value = self.read_prefix_target(target);
+        self.bit(bit, value);
+        self.pc.wrapping_add(2)
+    }
+    fn execute_res(&mut self, instruction: &Instruction) -> u16 {
+        let &Instruction::RESET(ref target, bit) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, |cpu, value| cpu.reset(bit, value))
+    }
+    fn execute_set(&mut self, instruction: &Instruction) -> u16 {
+        let &Instruction::SET(ref target, bit) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, |cpu, value| cpu.set(bit, value))
+    }
+    fn execute_srl(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::SRL(target) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, Self::srl)
+    }
+    fn execute_rr(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::RR(target) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, Self::rr)
+    }
+    fn execute_rl(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::RL(target) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, Self::rl)
+    }
+    fn execute_rrc(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::RRC(target) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, Self::rrc)
+    }
+    fn execute_rlc(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::RLC(target) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, Self::rlc)
+    }
+    fn execute_sra(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::SRA(target) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, Self::sra)
+    }
+    fn execute_sla(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::SLA(target) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, Self::sla)
+    }
+    fn execute_swap(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::SWAP(target) = instruction else { unreachable!() };
+        self.modify_prefix_target(target, Self::swap)
+    }
+    fn execute_add(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::ADD(target) = instruction else { unreachable!() };
+        self.accumulate(target, Self::add)
+    }
+    fn execute_adc(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::ADC(target) = instruction else { unreachable!() };
+        self.accumulate(target, Self::adc)
+    }
+    fn execute_sub(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::SUB(target) = instruction else { unreachable!() };
+        self.accumulate(target, Self::sub)
+    }
+    fn execute_sbc(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::SBC(target) = instruction else { unreachable!() };
+        self.accumulate(target, Self::sbc)
+    }
+    fn execute_and(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::AND(target) = instruction else { unreachable!() };
+        self.accumulate(target, Self::and)
+    }
+    fn execute_or(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::OR(target) = instruction else { unreachable!() };
+        self.accumulate(target, Self::or)
+    }
+    fn execute_xor(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::XOR(target) = instruction else { unreachable!() };
+        self.accumulate(target, Self::xor)
+    }
+    fn execute_cp(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::CP(target) = instruction else { unreachable!() };
+        // a subtraction that only keeps the flags
+        let value = self.read_arithmetic_target(target);
+        self.sub(value);
+        self.pc.wrapping_add(Self::arithmetic_length(target))
+    }
+    fn execute_inc(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::INC(target) = instruction else { unreachable!() };
+        self.step_inc_target(target, Self::inc8, Self::inc16)
+    }
+    fn execute_dec(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::DEC(target) = instruction else { unreachable!() };
+        self.step_inc_target(target, Self::dec8, Self::dec16)
+    }
+    fn execute_add_hl(&mut self, instruction: &Instruction) -> u16 {
+        let Instruction::AddHL(target) = instruction else { unreachable!() };
+        let value = match target {
+            ADDHLTarget::BC => self.register.get_bc(),
+            ADDHLTarget::DE => self.register.get_de(),
+            ADDHLTarget::HL => self.register.get_hl(),
+            ADDHLTarget::SP => self.sp,
+        };
+        // the high byte is added on a second internal cycle
+        self.tick();
+        self.add_hl(value);
+        self.pc.wrapping_add(1)
+    }
+    fn execute_add_sp(&mut self, _instruction: &Instruction) -> u16 {
+        let result = self.sp_plus_offset();
+        // SP is written a byte at a time, one internal cycle each
+        self.tick();
+        self.tick();
+        self.sp = result;
+        self.pc.wrapping_add(2)
+    }
+    fn execute_rra(&mut self, _instruction: &Instruction) -> u16 {
+        self.rra();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_rla(&mut self, _instruction: &Instruction) -> u16 {
+        self.rla();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_rrca(&mut self, _instruction: &Instruction) -> u16 {
+        self.rrca();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_rlca(&mut self, _instruction: &Instruction) -> u16 {
+        self.rrla();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_ccf(&mut self, _instruction: &Instruction) -> u16 {
+        self.ccf();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_scf(&mut self, _instruction: &Instruction) -> u16 {
+        self.scf();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_cpl(&mut self, _instruction: &Instruction) -> u16 {
+        self.cpl();
+        self.pc.wrapping_add(1)
+    }
+    fn execute_daa(&mut self, _instruction: &Instruction) -> u16 {
+        self.daa();
+        self.pc.wrapping_add(1)
+    }
+    /// The little-endian 16-bit immediate
+    fn read_operand_word(&mut self) -> u16 {
+        let low = self.read_operand(0) as u16;
+        let high = self.read_operand(1) as u16;
+        (high << 8) | low
+    }
+    /// SP plus the signed immediate, with the flags set from adding it to SP's low byte
+    fn sp_plus_offset(&mut self) -> u16 {
+        let offset = self.read_operand(0) as i8 as u16;
+        let sp = self.sp;
+        self.register.f.zero = false;
+        self.register.f.subtract = false;
+        self.register.f.half_carry = (sp & 0xF) + (offset & 0xF) > 0xF;
+        self.register.f.carry = (sp & 0xFF) + (offset & 0xFF) > 0xFF;
+        sp.wrapping_add(offset)
+    }
+    fn read_arithmetic_target(&mut self, target: &ArithmeticTarget) -> u8 {
+        match target {
+            ArithmeticTarget::A => self.register.a,
+            ArithmeticTarget::B => self.register.b,
+            ArithmeticTarget::C => self.register.c,
+            ArithmeticTarget::D => self.register.d,
+            ArithmeticTarget::E => self.register.e,
+            ArithmeticTarget::H => self.register.h,
+            ArithmeticTarget::L => self.register.l,
+            ArithmeticTarget::HL => self.read_cycle(self.register.get_hl()),
+            ArithmeticTarget::PC => self.read_operand(0),
+        }
+    }
+    fn arithmetic_length(target: &ArithmeticTarget) -> u16 {
+        match target {
+            ArithmeticTarget::PC => 2,
+            _ => 1,
+        }
+    }
+    /// A = operation(A, target)
+    fn accumulate(&mut self, target: &ArithmeticTarget, operation: fn(&mut Self, u8) -> u8) -> u16 {
+        let value = self.read_arithmetic_target(target);
+        self.register.a = operation(self, value);
+        self.pc.wrapping_add(Self::arithmetic_length(target))
+    }
+    /// INC or DEC on a register, a register pair or (HL)
+    fn step_inc_target(&mut self, target: &IncTarget, byte: fn(&mut Self, u8) -> u8, word: fn(&mut Self, u16) -> u16) -> u16 {
+        match target {
+            IncTarget::A => self.register.a = byte(self, self.register.a),
+            IncTarget::B => self.register.b = byte(self, self.register.b),
+            IncTarget::C => self.register.c = byte(self, self.register.c),
+            IncTarget::D => self.register.d = byte(self, self.register.d),
+            IncTarget::E => self.register.e = byte(self, self.register.e),
+            IncTarget::H => self.register.h = byte(self, self.register.h),
+            IncTarget::L => self.register.l = byte(self, self.register.l),
+            IncTarget::HLI => {
+                let address = self.register.get_hl();
+                let value = self.read_cycle(address);
+                let result = byte(self, value);
+                self.write_cycle(address, result);
             }
-            Instruction::PUSH(target) => {
-                let value = match target {
-                    StackTarget::BC => self.register.get_bc(),
-                    StackTarget::DE => self.register.get_de(),
-                    StackTarget::Hl => self.register.get_hl(),
-                    StackTarget::AF => self.register.get_af(),
-                };
-                self.push(value);
-                self.pc.wrapping_add(1)
+            IncTarget::BC => {
+                let result = word(self, self.register.get_bc());
+                self.register.set_bc(result);
             }
-            Instruction::ADDSP() => {
-                let value = self.bus.read_byte(self.pc) as i8 as i16;
-                self.pc = self.pc.wrapping_add(1);
-
-                let sp = self.sp;
-                let result = sp.wrapping_add(value as u16);
-
-                // Update flags according to Game Boy behavior
-                self.register.f.zero = false;
-                self.register.f.subtract = false;
-
-                // Half-carry occurs if lower nibble overflowed from bit 3 to 4
-                self.register.f.half_carry = ((sp & 0xF) + (value as u16 & 0xF)) > 0xF;
-
-                // Carry occurs if low byte overflowed from bit 7 to 8
-                self.register.f.carry = ((sp & 0xFF) + (value as u16 & 0xFF)) > 0xFF;
-
-                // Update SP (writes both lower and upper bytes)
-                self.sp = result;
-                self.pc.wrapping_add(2)
+            IncTarget::DE => {
+                let result = word(self, self.register.get_de());
+                self.register.set_de(result);
             }
-            Instruction::LDHL() => {
-                let value = self.bus.read_byte(self.pc) as i8 as i16;
-                self.pc = self.pc.wrapping_add(1);
-
-                let sp = self.sp;
-                let result = sp.wrapping_add(value as u16);
-
-                // Update flags according to Game Boy behavior
-                self.register.f.zero = false;
-                self.register.f.subtract = false;
-
-                // Half-carry occurs if lower nibble overflowed from bit 3 to 4
-                self.register.f.half_carry = ((sp & 0xF) + (value as u16 & 0xF)) > 0xF;
-
-                // Carry occurs if low byte overflowed from bit 7 to 8
-                self.register.f.carry = ((sp & 0xFF) + (value as u16 & 0xFF)) > 0xFF;
+            IncTarget::HL => {
+                let result = word(self, self.register.get_hl());
                 self.register.set_hl(result);
-                self.pc.wrapping_add(2)
-            }
-     Instruction::LDSP() => {
-         self.sp = self.register.get_hl();
-         self.pc.wrapping_add(1)
-     }
-            _=> {
-                self.pc.wrapping_add(1)
             }
+            IncTarget::SP => self.sp = word(self, self.sp),
+        }
+        self.pc.wrapping_add(1)
+    }
+    fn read_prefix_target(&mut self, target: &PrefixTarget) -> u8 {
+        match target {
+            PrefixTarget::A => self.register.a,
+            PrefixTarget::B => self.register.b,
+            PrefixTarget::C => self.register.c,
+            PrefixTarget::D => self.register.d,
+            PrefixTarget::E => self.register.e,
+            PrefixTarget::H => self.register.h,
+            PrefixTarget::L => self.register.l,
+            PrefixTarget::HL => self.read_cycle(self.register.get_hl()),
+        }
+    }
+    /// Reads the target of a CB instruction, runs it through `operation` and writes the result back
+    fn modify_prefix_target(&mut self, target: &PrefixTarget, operation: impl FnOnce(&mut Self, u8) -> u8) -> u16 {
+        let value = self.read_prefix_target(target);
+        let result = operation(self, value);
+        match target {
+            PrefixTarget::A => self.register.a = result,
+            PrefixTarget::B => self.register.b = result,
+            PrefixTarget::C => self.register.c = result,
+            PrefixTarget::D => self.register.d = result,
+            PrefixTarget::E => self.register.e = result,
+            PrefixTarget::H => self.register.h = result,
+            PrefixTarget::L => self.register.l = result,
+            PrefixTarget::HL => self.write_cycle(self.register.get_hl(), result),
         }
+        self.pc.wrapping_add(2)
     }
     fn add_hl(&mut self, value: u16) {
         let hl = self.register.get_hl();
@@ -1129,20 +879,22 @@ impl CPU {
         self.register.set_hl(sum);
     }
     fn pop(&mut self) -> u16 {
-        let lsb = self.bus.read_byte(self.sp) as u16;
+        let lsb = self.read_cycle(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
-        let msb = self.bus.read_byte(self.sp) as u16;
+        let msb = self.read_cycle(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
 
         (msb << 8) | lsb
     }
     fn push(&mut self, value: u16) {
+        // SP is decremented on an internal cycle before the first write
+        self.tick();
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.write_cycle(self.sp, ((value & 0xFF00) >> 8) as u8);
 
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, (value & 0xFF) as u8);
+        self.write_cycle(self.sp, (value & 0xFF) as u8);
     }
     fn and(&mut self, value: u8) -> u8 {
         let new_value = self.register.a & value;
@@ -1152,29 +904,98 @@ impl CPU {
         self.register.f.half_carry = true;
         new_value
     }
-    fn jump(&self, jump: bool) -> u16 {
+    fn jump(&mut self, jump: bool) -> u16 {
+        // the address is fetched even when the jump isn't taken
+        let least_significant_byte = self.read_operand(0) as u16;
+        let most_significant_byte = self.read_operand(1) as u16;
         if jump {
-            let least_significant_byte = self.bus.read_byte(self.pc + 1) as u16;
-            let most_significant_byte = self.bus.read_byte(self.pc + 2) as u16;
+            self.tick();
             (most_significant_byte << 8) | least_significant_byte
         } else {
             self.pc.wrapping_add(3)
         }
     }
+    fn jump_condition(&self, test: &JumpTest) -> bool {
+        match test {
+            JumpTest::NotZero => !self.register.f.zero,
+            JumpTest::NotCarry => !self.register.f.carry,
+            JumpTest::Zero => self.register.f.zero,
+            JumpTest::Carry => self.register.f.carry,
+            JumpTest::Always => true
+        }
+    }
+    fn jump_relative(&mut self, jump: bool) -> u16 {
+        // the offset is relative to the address of the next instruction
+        let next_pc = self.pc.wrapping_add(2);
+        let offset = self.read_operand(0) as i8;
+        if jump {
+            self.tick();
+            next_pc.wrapping_add(offset as u16)
+        } else {
+            next_pc
+        }
+    }
+    fn call(&mut self, jump: bool) -> u16 {
+        let next_pc = self.pc.wrapping_add(3);
+        let least_significant_byte = self.read_operand(0) as u16;
+        let most_significant_byte = self.read_operand(1) as u16;
+        if jump {
+            let target = (most_significant_byte << 8) | least_significant_byte;
+            self.push(next_pc);
+            self.push_frame(FrameKind::Call, target);
+            target
+        } else {
+            next_pc
+        }
+    }
+    fn ret(&mut self, jump: bool) -> u16 {
+        if jump {
+            self.call_stack.ret(self.sp);
+            let address = self.pop();
+            self.tick();
+            address
+        } else {
+            self.pc.wrapping_add(1)
+        }
+    }
     fn ccf(&mut self) {
+        self.register.f.subtract = false;
+        self.register.f.half_carry = false;
         self.register.f.carry = !self.register.f.carry;
     }
     fn scf(&mut self) {
+        self.register.f.subtract = false;
+        self.register.f.half_carry = false;
         self.register.f.carry = true;
     }
+    /// Turns A back into binary-coded decimal after an add or subtract of two BCD numbers
+    fn daa(&mut self) {
+        let mut adjust = 0;
+        let mut carry = self.register.f.carry;
+        if self.register.f.half_carry || (!self.register.f.subtract && self.register.a & 0x0F > 0x09) {
+            adjust |= 0x06;
+        }
+        if carry || (!self.register.f.subtract && self.register.a > 0x99) {
+            adjust |= 0x60;
+            carry = true;
+        }
+        self.register.a = if self.register.f.subtract {
+            self.register.a.wrapping_sub(adjust)
+        } else {
+            self.register.a.wrapping_add(adjust)
+        };
+        self.register.f.zero = self.register.a == 0;
+        self.register.f.half_carry = false;
+        self.register.f.carry = carry;
+    }
     fn rra(&mut self) {
         let carry_in = if self.register.f.carry { 0x80 } else { 0x00 };
         let bit0 = self.register.a & 0x01;
 
         self.register.a = (self.register.a >> 1) | carry_in;
 
-        // flags
-        self.register.f.zero = self.register.a == 0;
+        // flags, unlike RR A the zero flag is always cleared
+        self.register.f.zero = false;
         self.register.f.subtract = false;
         self.register.f.half_carry = false;
         self.register.f.carry = bit0 == 1;
@@ -1198,8 +1019,8 @@ impl CPU {
 
         self.register.a = (self.register.a << 1) | carry_in;
 
-        // flags
-        self.register.f.zero = self.register.a == 0;
+        // flags, unlike RL A the zero flag is always cleared
+        self.register.f.zero = false;
         self.register.f.subtract = false;
         self.register.f.half_carry = false;
         self.register.f.carry = bit7;
@@ -1221,8 +1042,8 @@ impl CPU {
         let bit0 = self.register.a & 0x01;
         self.register.f.carry = bit0 != 0;
         self.register.a = (self.register.a >> 1) | (bit0 << 7) ;
-        // flags
-        self.register.f.zero = self.register.a == 0;
+        // flags, unlike RRC A the zero flag is always cleared
+        self.register.f.zero = false;
         self.register.f.subtract = false;
         self.register.f.half_carry = false;
     }
@@ -1241,8 +1062,8 @@ impl CPU {
         self.register.f.carry = bit7 != 0;
         self.register.a = (self.register.a << 1) | (bit7 >> 7);
 
-        // flags
-        self.register.f.zero = self.register.a == 0;
+        // flags, unlike RLC A the zero flag is always cleared
+        self.register.f.zero = false;
         self.register.f.subtract = false;
         self.register.f.half_carry = false;
     }
@@ -1306,12 +1127,23 @@ impl CPU {
 
         new_value
     }
+    // the 16-bit incrementer needs an extra internal cycle, with the value on the address bus
     fn dec16(&mut self, values: u16) -> u16 {
+        self.tick();
+        self.oam_bug(values, OamCorruption::Write);
         values.wrapping_sub(1)
     }
     fn inc16(&mut self, values: u16) -> u16 {
+        self.tick();
+        self.oam_bug(values, OamCorruption::Write);
         values.wrapping_add(1)
     }
+    /// The Color models fixed the OAM bug
+    fn oam_bug(&mut self, address: u16, kind: OamCorruption) {
+        if !self.model.is_cgb() {
+            self.bus.trigger_oam_bug(address, kind);
+        }
+    }
 
     fn or(&mut self, value: u8) -> u8 {
         let new_value = self.register.a | value;
@@ -1347,24 +1179,23 @@ impl CPU {
         }
     fn adc(&mut self, value: u8) -> u8 {
         let carry =  if self.register.f.carry { 1 } else  { 0 };
-        let value_to_add = {
-            value + carry
-        };
-        let(new_value, did_overflow) = self.register.a.overflowing_add(value_to_add);
+        // the carry is added separately, value + carry can overflow on its own
+        let (partial, first_overflow) = self.register.a.overflowing_add(value);
+        let (new_value, second_overflow) = partial.overflowing_add(carry);
         self.register.f.zero = new_value == 0;
         self.register.f.subtract = false;
-        self.register.f.carry = did_overflow;
-        self.register.f.half_carry = (self.register.a & 0xF) + ((value + carry) & 0xF) > 0xF;
+        self.register.f.carry = first_overflow || second_overflow;
+        self.register.f.half_carry = (self.register.a & 0xF) + (value & 0xF) + carry > 0xF;
         new_value
     }
     fn sbc(&mut self, value: u8) -> u8 {
         let carry =  if self.register.f.carry { 1 } else  { 0 };
-        let value_to_sub = value + carry;
-        let (new_value, did_overflow) = self.register.a.overflowing_sub(value_to_sub);
+        let (partial, first_overflow) = self.register.a.overflowing_sub(value);
+        let (new_value, second_overflow) = partial.overflowing_sub(carry);
         self.register.f.zero = new_value == 0;
         self.register.f.subtract = true;
-        self.register.f.carry = did_overflow;
-        self.register.f.half_carry = (self.register.a & 0xF)  < ((value + carry) & 0xF);
+        self.register.f.carry = first_overflow || second_overflow;
+        self.register.f.half_carry = (self.register.a & 0xF) < (value & 0xF) + carry;
         new_value
     }
     fn sub(&mut self, value: u8) -> u8 {
@@ -1420,6 +1251,23 @@ impl From<u8> for FlagsRegister {
 
 }
 impl Register {
+    fn power_on() -> Register {
+        Register { a: 0, b: 0, c: 0, d: 0, e: 0, f: FlagsRegister::from(0), h: 0, l: 0 }
+    }
+    fn post_boot(model: Model, header_checksum: u8) -> Register {
+        // The DMG and MGB boot ROMs leave H and C set unless the header checksum is 0
+        let dmg_flags = if header_checksum == 0 { 0x80 } else { 0xB0 };
+        let (a, f, b, c, d, e, h, l) = match model {
+            Model::DMG0 => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            Model::DMG => (0x01, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::MGB => (0xFF, dmg_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            Model::SGB => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::SGB2 => (0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            Model::CGB => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            Model::AGB => (0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+        };
+        Register { a, b, c, d, e, f: FlagsRegister::from(f), h, l }
+    }
     fn get_hl(&self) -> u16 {
         ((self.h as u16) << 8) | self.l as u16
     }
@@ -1449,7 +1297,8 @@ impl Register {
     }
 
     fn set_af(&mut self, value: u16) {
-        let af: u16 = ((self.a as u16) << 8) | (u8::from(self.f) as u16);
-        self.f = FlagsRegister::from((af & 0xFF) as u8);
+        self.a = (value >> 8) as u8;
+        // the low nibble of F doesn't exist, FlagsRegister only keeps the top four bits
+        self.f = FlagsRegister::from((value & 0xF0) as u8);
     }
 }
@@ -0,0 +1,209 @@
+//! Checks the ALU helpers against a reference model of the SM83 flag rules written independently
+//! of them, over every 8-bit input and every incoming flag combination.
+//!
+//! These only cover the helpers, whether each opcode calls the right one is `dispatch_tests`' job.
+use super::{CPU, FlagsRegister};
+use crate::bus::MemoryBus;
+use crate::cartride::Cartridge;
+use crate::model::Model;
+
+type Cpu = CPU<MemoryBus>;
+
+const ZERO: u8 = 0x80;
+const CARRY: u8 = 0x10;
+
+fn cpu() -> Cpu {
+    CPU::new(MemoryBus::new(Cartridge::from_rom(vec![0; 0x8000]).unwrap()), Model::DMG)
+}
+
+fn flags(zero: bool, subtract: bool, half_carry: bool, carry: bool) -> u8 {
+    (zero as u8) << 7 | (subtract as u8) << 6 | (half_carry as u8) << 5 | (carry as u8) << 4
+}
+
+fn incoming_flags() -> impl Iterator<Item = u8> {
+    (0..=0xF0).step_by(0x10)
+}
+
+/// Runs `op` on every A, operand and incoming F, comparing the result and F with `model(a, value, f)`
+fn check_binary(name: &str, op: fn(&mut Cpu, u8) -> u8, model: fn(u8, u8, u8) -> (u8, u8)) {
+    let mut cpu = cpu();
+    for f in incoming_flags() {
+        for a in 0..=0xFF {
+            for value in 0..=0xFF {
+                cpu.register.a = a;
+                cpu.register.f = FlagsRegister::from(f);
+                let result = op(&mut cpu, value);
+                assert_eq!(
+                    (result, u8::from(cpu.register.f)),
+                    model(a, value, f),
+                    "{} a={:#04x} value={:#04x} f={:#04x}",
+                    name,
+                    a,
+                    value,
+                    f
+                );
+            }
+        }
+    }
+}
+
+/// Runs `op` on every operand and incoming F, comparing the result and F with `model(value, f)`
+fn check_unary(name: &str, op: fn(&mut Cpu, u8) -> u8, model: fn(u8, u8) -> (u8, u8)) {
+    let mut cpu = cpu();
+    for f in incoming_flags() {
+        for value in 0..=0xFF {
+            cpu.register.f = FlagsRegister::from(f);
+            let result = op(&mut cpu, value);
+            assert_eq!(
+                (result, u8::from(cpu.register.f)),
+                model(value, f),
+                "{} value={:#04x} f={:#04x}",
+                name,
+                value,
+                f
+            );
+        }
+    }
+}
+
+fn carry_in(f: u8) -> u8 {
+    (f & CARRY != 0) as u8
+}
+
+fn add_model(a: u8, value: u8, carry: u8) -> (u8, u8) {
+    let sum = a as u16 + value as u16 + carry as u16;
+    let half = (a & 0xF) + (value & 0xF) + carry > 0xF;
+    (sum as u8, flags(sum as u8 == 0, false, half, sum > 0xFF))
+}
+
+fn sub_model(a: u8, value: u8, carry: u8) -> (u8, u8) {
+    let difference = a as i16 - value as i16 - carry as i16;
+    let half = ((a & 0xF) as i16) - ((value & 0xF) as i16) - (carry as i16) < 0;
+    (difference as u8, flags(difference as u8 == 0, true, half, difference < 0))
+}
+
+/// Rotates and shifts: Z from the result, N and H cleared, C from the bit shifted out
+fn shift_model(result: u8, carry: bool) -> (u8, u8) {
+    (result, flags(result == 0, false, false, carry))
+}
+
+#[test]
+fn add() {
+    check_binary("add", Cpu::add, |a, value, _| add_model(a, value, 0));
+}
+
+#[test]
+fn adc() {
+    check_binary("adc", Cpu::adc, |a, value, f| add_model(a, value, carry_in(f)));
+}
+
+#[test]
+fn sub() {
+    check_binary("sub", Cpu::sub, |a, value, _| sub_model(a, value, 0));
+}
+
+#[test]
+fn sbc() {
+    check_binary("sbc", Cpu::sbc, |a, value, f| sub_model(a, value, carry_in(f)));
+}
+
+#[test]
+fn logic() {
+    check_binary("and", Cpu::and, |a, value, _| (a & value, flags(a & value == 0, false, true, false)));
+    check_binary("or", Cpu::or, |a, value, _| (a | value, flags(a | value == 0, false, false, false)));
+    check_binary("xor", Cpu::xor, |a, value, _| (a ^ value, flags(a ^ value == 0, false, false, false)));
+}
+
+#[test]
+fn inc8_and_dec8_leave_carry_alone() {
+    check_unary("inc8", Cpu::inc8, |value, f| {
+        let result = value.wrapping_add(1);
+        (result, flags(result == 0, false, value & 0xF == 0xF, false) | (f & CARRY))
+    });
+    check_unary("dec8", Cpu::dec8, |value, f| {
+        let result = value.wrapping_sub(1);
+        (result, flags(result == 0, true, value & 0xF == 0, false) | (f & CARRY))
+    });
+}
+
+#[test]
+fn add_hl_leaves_zero_alone() {
+    let mut cpu = cpu();
+    // every HL against a spread of values that hits both carries from every side
+    let values = (0..=0xFFFF).step_by(0x0FFF).chain([0x0001, 0x00FF, 0x0800, 0x0FFF, 0x1000, 0x8000, 0xFFFF]);
+    for value in values {
+        for f in [0x00, 0xF0] {
+            for hl in 0..=0xFFFF {
+                cpu.register.set_hl(hl);
+                cpu.register.f = FlagsRegister::from(f);
+                cpu.add_hl(value);
+                let sum = hl as u32 + value as u32;
+                let half = (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF;
+                let expected = flags(false, false, half, sum > 0xFFFF) | (f & ZERO);
+                assert_eq!(
+                    (cpu.register.get_hl(), u8::from(cpu.register.f)),
+                    (sum as u16, expected),
+                    "add_hl hl={:#06x} value={:#06x} f={:#04x}",
+                    hl,
+                    value,
+                    f
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn rotates_through_carry() {
+    check_unary("rl", Cpu::rl, |value, f| shift_model(value << 1 | carry_in(f), value & 0x80 != 0));
+    check_unary("rr", Cpu::rr, |value, f| shift_model(value >> 1 | carry_in(f) << 7, value & 0x01 != 0));
+}
+
+#[test]
+fn shifts() {
+    check_unary("sla", Cpu::sla, |value, _| shift_model(value << 1, value & 0x80 != 0));
+    check_unary("sra", Cpu::sra, |value, _| shift_model(value >> 1 | (value & 0x80), value & 0x01 != 0));
+    check_unary("srl", Cpu::srl, |value, _| shift_model(value >> 1, value & 0x01 != 0));
+    check_unary("swap", Cpu::swap, |value, _| shift_model(value.rotate_left(4), false));
+}
+
+#[test]
+fn bit_only_touches_zero_subtract_and_half_carry() {
+    let mut cpu = cpu();
+    for bit in 0..8 {
+        for f in incoming_flags() {
+            for value in 0..=0xFF {
+                cpu.register.f = FlagsRegister::from(f);
+                cpu.bit(bit, value);
+                let expected = flags(value & (1 << bit) == 0, false, true, false) | (f & CARRY);
+                assert_eq!(u8::from(cpu.register.f), expected, "bit {} value={:#04x} f={:#04x}", bit, value, f);
+            }
+        }
+    }
+}
+
+/// Runs an accumulator rotate on every A and incoming F, comparing A and F with `model(a, f)`
+fn check_accumulator(name: &str, op: fn(&mut Cpu), model: fn(u8, u8) -> (u8, u8)) {
+    let mut cpu = cpu();
+    for f in incoming_flags() {
+        for a in 0..=0xFF {
+            cpu.register.a = a;
+            cpu.register.f = FlagsRegister::from(f);
+            op(&mut cpu);
+            assert_eq!((cpu.register.a, u8::from(cpu.register.f)), model(a, f), "{} a={:#04x} f={:#04x}", name, a, f);
+        }
+    }
+}
+
+/// Unlike their CB-prefixed versions the accumulator rotates always clear the zero flag
+fn rotate_a_model(result: u8, carry: bool) -> (u8, u8) {
+    (result, flags(false, false, false, carry))
+}
+
+#[test]
+fn accumulator_rotates_clear_zero() {
+    check_accumulator("rlca", Cpu::rrla, |a, _| rotate_a_model(a.rotate_left(1), a & 0x80 != 0));
+    check_accumulator("rrca", Cpu::rrca, |a, _| rotate_a_model(a.rotate_right(1), a & 0x01 != 0));
+    check_accumulator("rla", Cpu::rla, |a, f| rotate_a_model(a << 1 | carry_in(f), a & 0x80 != 0));
+    check_accumulator("rra", Cpu::rra, |a, f| rotate_a_model(a >> 1 | carry_in(f) << 7, a & 0x01 != 0));
+}
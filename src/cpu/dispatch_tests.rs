@@ -0,0 +1,560 @@
+//! Runs every opcode through `CPU::step` and compares the registers, flags and bus activity with a
+//! reference interpreter that decodes the opcode bits itself, so a handler that calls the wrong
+//! helper or leaves PC in the wrong place shows up even when every ALU helper is right.
+use std::collections::HashMap;
+use super::{CPU, FlagsRegister};
+use super::test_bus::{Cycle, FlatBus};
+use crate::model::Model;
+
+const CASES_PER_OPCODE: u64 = 64;
+
+/// What's in memory before anything is written, different for every address and every case
+fn initial_byte(seed: u64, address: u16) -> u8 {
+    let mut x = seed ^ (address as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x ^= x >> 29;
+    x = x.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x ^= x >> 32;
+    x as u8
+}
+
+/// The registers both sides start from and are compared on, B C D E H L (HL) A order like the
+/// opcode encoding, the (HL) slot is unused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    r: [u8; 8],
+    f: u8,
+    sp: u16,
+    pc: u16,
+    ime: bool,
+}
+
+const A: usize = 7;
+const ZERO: u8 = 0x80;
+const SUBTRACT: u8 = 0x40;
+const HALF_CARRY: u8 = 0x20;
+const CARRY: u8 = 0x10;
+
+/// An SM83 interpreter written straight from the opcode bit patterns
+struct Reference {
+    regs: Registers,
+    seed: u64,
+    written: HashMap<u16, u8>,
+    cycles: Vec<Cycle>,
+}
+
+impl Reference {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.written.get(&address).copied().unwrap_or_else(|| initial_byte(self.seed, address));
+        self.cycles.push(Cycle::Read(address, value));
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.written.insert(address, value);
+        self.cycles.push(Cycle::Write(address, value));
+    }
+
+    fn idle(&mut self) {
+        self.cycles.push(Cycle::Idle);
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let value = self.read(self.regs.pc);
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        value
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch() as u16;
+        let high = self.fetch() as u16;
+        high << 8 | low
+    }
+
+    fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.regs.r[4], self.regs.r[5]])
+    }
+
+    fn set_hl(&mut self, value: u16) {
+        [self.regs.r[4], self.regs.r[5]] = value.to_be_bytes();
+    }
+
+    /// BC, DE, HL, SP
+    fn rp(&self, p: u8) -> u16 {
+        match p {
+            3 => self.regs.sp,
+            _ => u16::from_be_bytes([self.regs.r[p as usize * 2], self.regs.r[p as usize * 2 + 1]]),
+        }
+    }
+
+    fn set_rp(&mut self, p: u8, value: u16) {
+        match p {
+            3 => self.regs.sp = value,
+            _ => [self.regs.r[p as usize * 2], self.regs.r[p as usize * 2 + 1]] = value.to_be_bytes(),
+        }
+    }
+
+    /// BC, DE, HL, AF
+    fn rp2(&self, p: u8) -> u16 {
+        match p {
+            3 => u16::from_be_bytes([self.regs.r[A], self.regs.f]),
+            _ => self.rp(p),
+        }
+    }
+
+    fn set_rp2(&mut self, p: u8, value: u16) {
+        match p {
+            3 => {
+                let [a, f] = value.to_be_bytes();
+                self.regs.r[A] = a;
+                self.regs.f = f & 0xF0;
+            }
+            _ => self.set_rp(p, value),
+        }
+    }
+
+    /// Index 6 is (HL), which costs a memory access
+    fn get_r(&mut self, index: u8) -> u8 {
+        match index {
+            6 => self.read(self.hl()),
+            _ => self.regs.r[index as usize],
+        }
+    }
+
+    fn set_r(&mut self, index: u8, value: u8) {
+        match index {
+            6 => self.write(self.hl(), value),
+            _ => self.regs.r[index as usize] = value,
+        }
+    }
+
+    fn flag(&self, mask: u8) -> bool {
+        self.regs.f & mask != 0
+    }
+
+    fn set_flags(&mut self, zero: bool, subtract: bool, half_carry: bool, carry: bool) {
+        self.regs.f = (zero as u8) << 7 | (subtract as u8) << 6 | (half_carry as u8) << 5 | (carry as u8) << 4;
+    }
+
+    /// NZ, Z, NC, C
+    fn condition(&self, cc: u8) -> bool {
+        match cc {
+            0 => !self.flag(ZERO),
+            1 => self.flag(ZERO),
+            2 => !self.flag(CARRY),
+            _ => self.flag(CARRY),
+        }
+    }
+
+    fn push(&mut self, value: u16) {
+        let [high, low] = value.to_be_bytes();
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, high);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, low);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let low = self.read(self.regs.sp) as u16;
+        self.regs.sp = self.regs.sp.wrapping_add(1);
+        let high = self.read(self.regs.sp) as u16;
+        self.regs.sp = self.regs.sp.wrapping_add(1);
+        high << 8 | low
+    }
+
+    /// ADD ADC SUB SBC AND XOR OR CP
+    fn alu(&mut self, operation: u8, value: u8) {
+        let a = self.regs.r[A];
+        let carry = self.flag(CARRY) as u8;
+        match operation {
+            0 | 1 => {
+                let carry = if operation == 1 { carry } else { 0 };
+                let sum = a as u16 + value as u16 + carry as u16;
+                self.regs.r[A] = sum as u8;
+                self.set_flags(sum as u8 == 0, false, (a & 0xF) + (value & 0xF) + carry > 0xF, sum > 0xFF);
+            }
+            2 | 3 | 7 => {
+                let carry = if operation == 3 { carry } else { 0 };
+                let difference = a as i16 - value as i16 - carry as i16;
+                let half = ((a & 0xF) as i16) - ((value & 0xF) as i16) - (carry as i16) < 0;
+                if operation != 7 {
+                    self.regs.r[A] = difference as u8;
+                }
+                self.set_flags(difference as u8 == 0, true, half, difference < 0);
+            }
+            4 => {
+                self.regs.r[A] = a & value;
+                self.set_flags(a & value == 0, false, true, false);
+            }
+            5 => {
+                self.regs.r[A] = a ^ value;
+                self.set_flags(a ^ value == 0, false, false, false);
+            }
+            _ => {
+                self.regs.r[A] = a | value;
+                self.set_flags(a | value == 0, false, false, false);
+            }
+        }
+    }
+
+    /// SP plus a signed offset, with H and C from the low byte as an unsigned add
+    fn sp_offset(&mut self) -> u16 {
+        let offset = self.fetch();
+        let sp = self.regs.sp;
+        let half = (sp & 0xF) + (offset as u16 & 0xF) > 0xF;
+        let carry = (sp & 0xFF) + offset as u16 > 0xFF;
+        self.set_flags(false, false, half, carry);
+        sp.wrapping_add(offset as i8 as u16)
+    }
+
+    fn step(&mut self) {
+        let opcode = self.fetch();
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let (p, q) = (y >> 1, y & 1);
+        match (x, z) {
+            (0, 0) => match y {
+                0 => {}
+                1 => {
+                    let address = self.fetch_word();
+                    let [high, low] = self.regs.sp.to_be_bytes();
+                    self.write(address, low);
+                    self.write(address.wrapping_add(1), high);
+                }
+                _ => {
+                    let offset = self.fetch() as i8;
+                    if y == 3 || self.condition(y - 4) {
+                        self.idle();
+                        self.regs.pc = self.regs.pc.wrapping_add(offset as u16);
+                    }
+                }
+            },
+            (0, 1) if q == 0 => {
+                let value = self.fetch_word();
+                self.set_rp(p, value);
+            }
+            (0, 1) => {
+                let (hl, value) = (self.hl(), self.rp(p));
+                self.idle();
+                let zero = self.flag(ZERO);
+                self.set_flags(zero, false, (hl & 0xFFF) + (value & 0xFFF) > 0xFFF, hl as u32 + value as u32 > 0xFFFF);
+                self.set_hl(hl.wrapping_add(value));
+            }
+            (0, 2) => {
+                let address = match p {
+                    0 | 1 => self.rp(p),
+                    _ => self.hl(),
+                };
+                if q == 0 {
+                    self.write(address, self.regs.r[A]);
+                } else {
+                    self.regs.r[A] = self.read(address);
+                }
+                match p {
+                    2 => self.set_hl(address.wrapping_add(1)),
+                    3 => self.set_hl(address.wrapping_sub(1)),
+                    _ => {}
+                }
+            }
+            (0, 3) => {
+                let value = self.rp(p);
+                self.idle();
+                self.set_rp(p, if q == 0 { value.wrapping_add(1) } else { value.wrapping_sub(1) });
+            }
+            (0, 4) | (0, 5) => {
+                let value = self.get_r(y);
+                let carry = self.flag(CARRY);
+                let result = if z == 4 {
+                    self.set_flags(value == 0xFF, false, value & 0xF == 0xF, carry);
+                    value.wrapping_add(1)
+                } else {
+                    self.set_flags(value == 0x01, true, value & 0xF == 0, carry);
+                    value.wrapping_sub(1)
+                };
+                self.set_r(y, result);
+            }
+            (0, 6) => {
+                let value = self.fetch();
+                self.set_r(y, value);
+            }
+            (0, 7) => {
+                let a = self.regs.r[A];
+                let carry = self.flag(CARRY);
+                match y {
+                    0 => {
+                        self.regs.r[A] = a.rotate_left(1);
+                        self.set_flags(false, false, false, a & 0x80 != 0);
+                    }
+                    1 => {
+                        self.regs.r[A] = a.rotate_right(1);
+                        self.set_flags(false, false, false, a & 0x01 != 0);
+                    }
+                    2 => {
+                        self.regs.r[A] = a << 1 | carry as u8;
+                        self.set_flags(false, false, false, a & 0x80 != 0);
+                    }
+                    3 => {
+                        self.regs.r[A] = a >> 1 | (carry as u8) << 7;
+                        self.set_flags(false, false, false, a & 0x01 != 0);
+                    }
+                    4 => {
+                        let (subtract, half) = (self.flag(SUBTRACT), self.flag(HALF_CARRY));
+                        let mut adjust = 0;
+                        let mut carry_out = carry;
+                        if half || (!subtract && a & 0xF > 9) {
+                            adjust |= 0x06;
+                        }
+                        if carry || (!subtract && a > 0x99) {
+                            adjust |= 0x60;
+                            carry_out = true;
+                        }
+                        let result = if subtract { a.wrapping_sub(adjust) } else { a.wrapping_add(adjust) };
+                        self.regs.r[A] = result;
+                        self.set_flags(result == 0, subtract, false, carry_out);
+                    }
+                    5 => {
+                        self.regs.r[A] = !a;
+                        self.regs.f |= SUBTRACT | HALF_CARRY;
+                    }
+                    6 => {
+                        let zero = self.flag(ZERO);
+                        self.set_flags(zero, false, false, true);
+                    }
+                    _ => {
+                        let zero = self.flag(ZERO);
+                        self.set_flags(zero, false, false, !carry);
+                    }
+                }
+            }
+            (1, _) => {
+                let value = self.get_r(z);
+                self.set_r(y, value);
+            }
+            (2, _) => {
+                let value = self.get_r(z);
+                self.alu(y, value);
+            }
+            (3, 0) => match y {
+                0..=3 => {
+                    self.idle();
+                    if self.condition(y) {
+                        self.regs.pc = self.pop();
+                        self.idle();
+                    }
+                }
+                4 => {
+                    let offset = self.fetch();
+                    self.write(0xFF00 | offset as u16, self.regs.r[A]);
+                }
+                5 => {
+                    let result = self.sp_offset();
+                    self.idle();
+                    self.idle();
+                    self.regs.sp = result;
+                }
+                6 => {
+                    let offset = self.fetch();
+                    self.regs.r[A] = self.read(0xFF00 | offset as u16);
+                }
+                _ => {
+                    let result = self.sp_offset();
+                    self.idle();
+                    self.set_hl(result);
+                }
+            },
+            (3, 1) if q == 0 => {
+                let value = self.pop();
+                self.set_rp2(p, value);
+            }
+            (3, 1) => match p {
+                0 | 1 => {
+                    self.regs.pc = self.pop();
+                    self.idle();
+                    if p == 1 {
+                        self.regs.ime = true;
+                    }
+                }
+                2 => self.regs.pc = self.hl(),
+                _ => {
+                    self.idle();
+                    self.regs.sp = self.hl();
+                }
+            },
+            (3, 2) => match y {
+                0..=3 => {
+                    let address = self.fetch_word();
+                    if self.condition(y) {
+                        self.idle();
+                        self.regs.pc = address;
+                    }
+                }
+                4 => self.write(0xFF00 | self.regs.r[1] as u16, self.regs.r[A]),
+                5 => {
+                    let address = self.fetch_word();
+                    self.write(address, self.regs.r[A]);
+                }
+                6 => self.regs.r[A] = self.read(0xFF00 | self.regs.r[1] as u16),
+                _ => {
+                    let address = self.fetch_word();
+                    self.regs.r[A] = self.read(address);
+                }
+            },
+            (3, 3) => match y {
+                0 => {
+                    let address = self.fetch_word();
+                    self.idle();
+                    self.regs.pc = address;
+                }
+                1 => self.step_prefixed(),
+                6 => self.regs.ime = false,
+                _ => {} // EI only takes effect after the next instruction
+            },
+            (3, 4) | (3, 5) => {
+                if z == 5 && q == 0 {
+                    let value = self.rp2(p);
+                    self.idle();
+                    self.push(value);
+                    return;
+                }
+                let address = self.fetch_word();
+                if z == 5 || self.condition(y) {
+                    self.idle();
+                    self.push(self.regs.pc);
+                    self.regs.pc = address;
+                }
+            }
+            (3, 6) => {
+                let value = self.fetch();
+                self.alu(y, value);
+            }
+            _ => {
+                self.idle();
+                self.push(self.regs.pc);
+                self.regs.pc = y as u16 * 8;
+            }
+        }
+    }
+
+    fn step_prefixed(&mut self) {
+        let opcode = self.fetch();
+        let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, opcode & 7);
+        let value = self.get_r(z);
+        let carry = self.flag(CARRY);
+        let result = match x {
+            0 => {
+                let (result, carry_out) = match y {
+                    0 => (value.rotate_left(1), value & 0x80 != 0),
+                    1 => (value.rotate_right(1), value & 0x01 != 0),
+                    2 => (value << 1 | carry as u8, value & 0x80 != 0),
+                    3 => (value >> 1 | (carry as u8) << 7, value & 0x01 != 0),
+                    4 => (value << 1, value & 0x80 != 0),
+                    5 => (value >> 1 | (value & 0x80), value & 0x01 != 0),
+                    6 => (value.rotate_left(4), false),
+                    _ => (value >> 1, value & 0x01 != 0),
+                };
+                self.set_flags(result == 0, false, false, carry_out);
+                result
+            }
+            1 => {
+                self.set_flags(value & (1 << y) == 0, false, true, carry);
+                return;
+            }
+            2 => value & !(1 << y),
+            _ => value | (1 << y),
+        };
+        self.set_r(z, result);
+    }
+}
+
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn random_registers(random: &mut Random) -> Registers {
+    let bytes = random.next().to_le_bytes();
+    let words = random.next();
+    Registers {
+        r: [bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], 0, bytes[6]],
+        f: bytes[7] & 0xF0,
+        sp: words as u16,
+        pc: (words >> 16) as u16,
+        ime: words & 1 << 40 != 0,
+    }
+}
+
+fn cpu_registers(cpu: &CPU<FlatBus>) -> Registers {
+    let r = &cpu.register;
+    Registers {
+        r: [r.b, r.c, r.d, r.e, r.h, r.l, 0, r.a],
+        f: u8::from(r.f),
+        sp: cpu.sp,
+        pc: cpu.pc,
+        ime: cpu.bus.interrupts.ime,
+    }
+}
+
+/// Runs one opcode from `regs` on both sides and describes the first difference
+fn compare(opcode: &[u8], regs: Registers, seed: u64) -> Option<String> {
+    let mut written = HashMap::new();
+    for (offset, &byte) in opcode.iter().enumerate() {
+        written.insert(regs.pc.wrapping_add(offset as u16), byte);
+    }
+
+    let mut reference = Reference { regs, seed, written: written.clone(), cycles: Vec::new() };
+    reference.step();
+
+    let mut bus = FlatBus::filled(move |address| initial_byte(seed, address));
+    for (address, value) in written {
+        bus.poke(address, value);
+    }
+    let mut cpu = CPU::new(bus, Model::DMG);
+    let r = &mut cpu.register;
+    [r.b, r.c, r.d, r.e, r.h, r.l] = [regs.r[0], regs.r[1], regs.r[2], regs.r[3], regs.r[4], regs.r[5]];
+    r.a = regs.r[A];
+    r.f = FlagsRegister::from(regs.f);
+    cpu.sp = regs.sp;
+    cpu.pc = regs.pc;
+    cpu.bus.interrupts.ime = regs.ime;
+    cpu.bus.cycles.get_mut().clear();
+    if let Err(error) = cpu.step() {
+        return Some(error.to_string());
+    }
+
+    let actual = cpu_registers(&cpu);
+    if actual != reference.regs {
+        return Some(format!("from {:x?}\n      got {:x?}\n expected {:x?}", regs, actual, reference.regs));
+    }
+    let cycles = cpu.bus.cycles.into_inner();
+    if cycles != reference.cycles {
+        return Some(format!("from {:x?}\n bus {:x?}\n expected {:x?}", regs, cycles, reference.cycles));
+    }
+    None
+}
+
+/// Everything but the prefix itself, HALT, STOP and the opcodes that lock the CPU up
+fn testable(byte: u8) -> bool {
+    !matches!(byte, 0x10 | 0x76 | 0xCB | 0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD)
+}
+
+#[test]
+fn every_opcode_matches_the_reference() {
+    let mut random = Random(0x2545_F491_4F6C_DD1D);
+    let mut failures = Vec::new();
+    let opcodes = (0..=0xFF).filter(|&byte| testable(byte)).map(|byte| vec![byte]);
+    let prefixed = (0..=0xFF).map(|byte| vec![0xCB, byte]);
+    for opcode in opcodes.chain(prefixed) {
+        for _ in 0..CASES_PER_OPCODE {
+            let regs = random_registers(&mut random);
+            if let Some(failure) = compare(&opcode, regs, random.next()) {
+                failures.push(format!("{:02x?}: {}", opcode, failure));
+                break;
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{} opcodes differ:\n{}", failures.len(), failures.join("\n"));
+}
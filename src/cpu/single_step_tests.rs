@@ -0,0 +1,196 @@
+//! Runs the SingleStepTests sm83 suite (https://github.com/SingleStepTests/sm83) against the CPU.
+//!
+//! The JSON files aren't checked in, clone the suite and point `SM83_TESTS` at its `v1` directory
+//! (or drop the files in `tests/sm83`), then run `cargo test -- --ignored single_step`. The test
+//! fails when it can't find them, so a passing run always means the suite actually ran.
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use super::{CPU, FlagsRegister};
+use super::test_bus::{Cycle, FlatBus};
+use crate::model::Model;
+
+const DEFAULT_TESTS_DIR: &str = "tests/sm83";
+// Stop listing failures for an opcode after this many, one broken opcode fails all 1000 of its tests
+const MAX_REPORTED_PER_FILE: usize = 3;
+
+/// The register and memory state at either end of a test
+struct State {
+    a: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    f: u8,
+    h: u8,
+    l: u8,
+    pc: u16,
+    sp: u16,
+    ime: bool,
+    ram: Vec<(u16, u8)>,
+}
+
+impl State {
+    fn parse(json: &Value) -> State {
+        let number = |key: &str| json[key].as_u64().unwrap_or_else(|| panic!("missing \"{}\"", key));
+        let ram = json["ram"]
+            .as_array()
+            .expect("missing \"ram\"")
+            .iter()
+            .map(|pair| (pair[0].as_u64().unwrap() as u16, pair[1].as_u64().unwrap() as u8))
+            .collect();
+        State {
+            a: number("a") as u8,
+            b: number("b") as u8,
+            c: number("c") as u8,
+            d: number("d") as u8,
+            e: number("e") as u8,
+            f: number("f") as u8,
+            h: number("h") as u8,
+            l: number("l") as u8,
+            pc: number("pc") as u16,
+            sp: number("sp") as u16,
+            ime: json["ime"].as_u64().unwrap_or(0) != 0,
+            ram,
+        }
+    }
+
+    fn load(&self, cpu: &mut CPU<FlatBus>) {
+        cpu.register.a = self.a;
+        cpu.register.b = self.b;
+        cpu.register.c = self.c;
+        cpu.register.d = self.d;
+        cpu.register.e = self.e;
+        cpu.register.f = FlagsRegister::from(self.f);
+        cpu.register.h = self.h;
+        cpu.register.l = self.l;
+        cpu.pc = self.pc;
+        cpu.sp = self.sp;
+        cpu.bus.interrupts.ime = self.ime;
+        for &(address, value) in &self.ram {
+            cpu.bus.poke(address, value);
+        }
+    }
+
+    /// Lists every register and memory location that doesn't match
+    fn diff(&self, cpu: &CPU<FlatBus>) -> Vec<String> {
+        let registers = [
+            ("a", self.a as u16, cpu.register.a as u16),
+            ("b", self.b as u16, cpu.register.b as u16),
+            ("c", self.c as u16, cpu.register.c as u16),
+            ("d", self.d as u16, cpu.register.d as u16),
+            ("e", self.e as u16, cpu.register.e as u16),
+            ("f", self.f as u16, u8::from(cpu.register.f) as u16),
+            ("h", self.h as u16, cpu.register.h as u16),
+            ("l", self.l as u16, cpu.register.l as u16),
+            ("pc", self.pc, cpu.pc),
+            ("sp", self.sp, cpu.sp),
+            ("ime", self.ime as u16, cpu.bus.interrupts.ime as u16),
+        ];
+        let mut mismatches: Vec<String> = registers
+            .iter()
+            .filter(|(_, expected, actual)| expected != actual)
+            .map(|(name, expected, actual)| format!("{} = {:#x}, expected {:#x}", name, actual, expected))
+            .collect();
+        for &(address, expected) in &self.ram {
+            let actual = cpu.bus.peek(address);
+            if actual != expected {
+                mismatches.push(format!("[{:#06x}] = {:#04x}, expected {:#04x}", address, actual, expected));
+            }
+        }
+        mismatches
+    }
+}
+
+/// Entries look like [address, data, "r-m"], the pins string says whether it was a read or a write
+fn parse_cycles(json: &Value) -> Vec<Cycle> {
+    json.as_array()
+        .map(|cycles| cycles.iter().map(parse_cycle).collect())
+        .unwrap_or_default()
+}
+
+fn parse_cycle(entry: &Value) -> Cycle {
+    let address = entry[0].as_u64().map(|address| address as u16);
+    let data = entry[1].as_u64().map(|data| data as u8);
+    let pins = entry[2].as_str().unwrap_or("---").as_bytes();
+    match (address, data, pins) {
+        (Some(address), Some(data), [b'r', ..]) => Cycle::Read(address, data),
+        (Some(address), Some(data), [_, b'w', ..]) => Cycle::Write(address, data),
+        _ => Cycle::Idle,
+    }
+}
+
+/// Runs one test case and describes what went wrong, if anything
+fn run_case(case: &Value) -> Option<String> {
+    let initial = State::parse(&case["initial"]);
+    let expected = State::parse(&case["final"]);
+    let expected_cycles = parse_cycles(&case["cycles"]);
+
+    let mut cpu = CPU::new(FlatBus::new(), Model::DMG);
+    initial.load(&mut cpu);
+    cpu.bus.cycles.get_mut().clear();
+
+    // a panic inside one opcode shouldn't hide the results for all the others
+    let mut mismatches = match panic::catch_unwind(AssertUnwindSafe(|| cpu.step())) {
+        Ok(Ok(_)) => expected.diff(&cpu),
+        Ok(Err(error)) => vec![error.to_string()],
+        Err(_) => vec!["panicked".to_string()],
+    };
+    let cycles = cpu.bus.cycles.into_inner();
+    if cycles != expected_cycles {
+        mismatches.push(format!("bus activity {:?}, expected {:?}", cycles, expected_cycles));
+    }
+    if mismatches.is_empty() {
+        None
+    } else {
+        Some(format!("{}: {}", case["name"].as_str().unwrap_or("?"), mismatches.join(", ")))
+    }
+}
+
+/// Runs every case in one opcode's file and returns the failures worth reporting
+fn run_file(path: &Path) -> (usize, Vec<String>) {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| panic!("can't read {}: {}", path.display(), e));
+    let cases: Vec<Value> = serde_json::from_str(&text).unwrap_or_else(|e| panic!("bad JSON in {}: {}", path.display(), e));
+    let mut failed = 0;
+    let mut reported = Vec::new();
+    for case in &cases {
+        if let Some(failure) = run_case(case) {
+            failed += 1;
+            if reported.len() < MAX_REPORTED_PER_FILE {
+                reported.push(failure);
+            }
+        }
+    }
+    (failed, reported)
+}
+
+fn tests_dir() -> Option<PathBuf> {
+    let dir = std::env::var_os("SM83_TESTS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join(DEFAULT_TESTS_DIR));
+    dir.is_dir().then_some(dir)
+}
+
+#[test]
+#[ignore = "needs the SingleStepTests JSON files, see the module docs"]
+fn single_step_tests() {
+    let dir = tests_dir().expect("sm83 test files not found, set SM83_TESTS or put them in tests/sm83");
+    let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty(), "no .json files in {}", dir.display());
+
+    let mut report = Vec::new();
+    for path in &files {
+        let (failed, reported) = run_file(path);
+        if failed > 0 {
+            let name = path.file_stem().unwrap().to_string_lossy();
+            report.push(format!("{} ({} failed)\n    {}", name, failed, reported.join("\n    ")));
+        }
+    }
+    assert!(report.is_empty(), "{} of {} opcodes failed:\n{}", report.len(), files.len(), report.join("\n"));
+}
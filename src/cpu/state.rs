@@ -0,0 +1,115 @@
+use super::{CARRY_FLAG_BYTE_POSITION, HALF_CARRY_FLAG_BYTE_POSITION, SUBTRACT_FLAG_BYTE_POSITION, ZERO_FLAG_BYTE_POSITION};
+
+/// A copy of everything the CPU itself holds, taken with `CPU::state` and put back with
+/// `CPU::set_state` so debuggers, save states and tests never see half of an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuState {
+    a: u8,
+    f: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    h: u8,
+    l: u8,
+    sp: u16,
+    pc: u16,
+    ime: bool,
+    halted: bool,
+}
+
+macro_rules! byte_register {
+    ($get:ident, $set:ident) => {
+        pub fn $get(&self) -> u8 {
+            self.$get
+        }
+        pub fn $set(&mut self, value: u8) {
+            self.$get = value;
+        }
+    };
+}
+
+macro_rules! register_pair {
+    ($get:ident, $set:ident, $high:ident, $low:ident) => {
+        pub fn $get(&self) -> u16 {
+            u16::from_be_bytes([self.$high, self.$low])
+        }
+        pub fn $set(&mut self, value: u16) {
+            [self.$high, self.$low] = value.to_be_bytes();
+        }
+    };
+}
+
+macro_rules! flag {
+    ($get:ident, $set:ident, $position:ident) => {
+        pub fn $get(&self) -> bool {
+            self.f & (1 << $position) != 0
+        }
+        pub fn $set(&mut self, value: bool) {
+            self.f = (self.f & !(1 << $position)) | ((value as u8) << $position);
+        }
+    };
+}
+
+impl CpuState {
+    byte_register!(a, set_a);
+    byte_register!(b, set_b);
+    byte_register!(c, set_c);
+    byte_register!(d, set_d);
+    byte_register!(e, set_e);
+    byte_register!(h, set_h);
+    byte_register!(l, set_l);
+
+    pub fn f(&self) -> u8 {
+        self.f
+    }
+    /// The low nibble of F doesn't exist and always reads back as 0
+    pub fn set_f(&mut self, value: u8) {
+        self.f = value & 0xF0;
+    }
+
+    register_pair!(bc, set_bc, b, c);
+    register_pair!(de, set_de, d, e);
+    register_pair!(hl, set_hl, h, l);
+
+    pub fn af(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.f])
+    }
+    pub fn set_af(&mut self, value: u16) {
+        let [a, f] = value.to_be_bytes();
+        self.a = a;
+        self.set_f(f);
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+    pub fn set_sp(&mut self, value: u16) {
+        self.sp = value;
+    }
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    flag!(zero, set_zero, ZERO_FLAG_BYTE_POSITION);
+    flag!(subtract, set_subtract, SUBTRACT_FLAG_BYTE_POSITION);
+    flag!(half_carry, set_half_carry, HALF_CARRY_FLAG_BYTE_POSITION);
+    flag!(carry, set_carry, CARRY_FLAG_BYTE_POSITION);
+
+    /// Setting IME here takes effect straight away, unlike EI
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+    pub fn set_ime(&mut self, value: bool) {
+        self.ime = value;
+    }
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+    pub fn set_halted(&mut self, value: bool) {
+        self.halted = value;
+    }
+}
@@ -0,0 +1,83 @@
+//! The bus the opcode tests run the CPU on, it logs what happens on every machine cycle so the
+//! tests can compare the CPU's bus activity and not just where it ends up.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use crate::bus::Bus;
+use crate::interrupts::InterruptController;
+
+/// What happened on the bus during one machine cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Cycle {
+    Idle,
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+/// 64KiB of plain RAM with nothing mapped into it, logging every access it sees. Only what's written
+/// is stored, everything else reads as whatever `fill` says is there
+pub(super) struct FlatBus {
+    written: HashMap<u16, u8>,
+    fill: Box<dyn Fn(u16) -> u8>,
+    pub(super) interrupts: InterruptController,
+    pub(super) cycles: RefCell<Vec<Cycle>>,
+}
+
+impl FlatBus {
+    /// Memory that reads 0 until it's written
+    pub(super) fn new() -> Self {
+        Self::filled(|_| 0)
+    }
+
+    pub(super) fn filled(fill: impl Fn(u16) -> u8 + 'static) -> Self {
+        FlatBus {
+            written: HashMap::new(),
+            fill: Box::new(fill),
+            interrupts: InterruptController::new(),
+            cycles: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Reads memory without it showing up in `cycles`
+    pub(super) fn peek(&self, address: u16) -> u8 {
+        self.written.get(&address).copied().unwrap_or_else(|| (self.fill)(address))
+    }
+
+    /// Writes memory without it showing up in `cycles`
+    pub(super) fn poke(&mut self, address: u16, value: u8) {
+        self.written.insert(address, value);
+    }
+
+    /// The CPU ticks before each access, so an access fills in the cycle its tick just opened
+    fn record(&self, cycle: Cycle) {
+        if let Some(last @ Cycle::Idle) = self.cycles.borrow_mut().last_mut() {
+            *last = cycle;
+        }
+    }
+}
+
+impl Bus for FlatBus {
+    fn read_byte(&self, address: u16) -> u8 {
+        let value = self.peek(address);
+        self.record(Cycle::Read(address, value));
+        value
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.poke(address, value);
+        self.record(Cycle::Write(address, value));
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles / 4 {
+            self.cycles.get_mut().push(Cycle::Idle);
+        }
+    }
+
+    fn interrupts(&self) -> &InterruptController {
+        &self.interrupts
+    }
+
+    fn interrupts_mut(&mut self) -> &mut InterruptController {
+        &mut self.interrupts
+    }
+}
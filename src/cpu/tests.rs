@@ -0,0 +1,994 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use super::{CpuError, CPU};
+use crate::assembler::assemble_rom;
+use crate::bus::{Bus, MemoryBus};
+use crate::device::BusDevice;
+use crate::hooks::Hooks;
+use crate::interrupts::InterruptSource;
+use crate::keypad::Key;
+use crate::cartride::Cartridge;
+use crate::model::Model;
+
+fn machine(source: &str) -> CPU {
+    let rom = assemble_rom(source).unwrap_or_else(|error| panic!("{}", error));
+    CPU::new(MemoryBus::new(Cartridge::from_rom(rom).unwrap()), Model::DMG)
+}
+
+/// Steps until the CPU halts, panicking if it takes longer than `max_steps`
+fn run_until_halt(cpu: &mut CPU, max_steps: usize) {
+    for _ in 0..max_steps {
+        cpu.step().unwrap();
+        if cpu.is_halted {
+            return;
+        }
+    }
+    panic!("still running at {:#06x}", cpu.pc);
+}
+
+#[test]
+fn call_returns_past_itself() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld sp, $fffe
+                call load
+                ld b, a
+                halt
+        load:   ld a, $42
+                ret
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.register.b, 0x42);
+    assert_eq!(cpu.sp, 0xFFFE);
+}
+
+#[test]
+fn call_returns_with_the_stack_at_the_top_of_work_ram() {
+    // where Blargg's runtime keeps its stack
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld sp, $dfff
+                call load
+                ld b, a
+                halt
+        load:   ld a, $42
+                ret
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.register.b, 0x42);
+    assert_eq!(cpu.sp, 0xDFFF);
+}
+
+#[test]
+fn prefixed_instructions_step_over_both_bytes() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld a, $f0
+                swap a          ; cb 37, the 37 on its own would be SCF
+                halt
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.register.a, 0x0F);
+    assert!(!cpu.register.f.carry);
+    assert_eq!(cpu.pc, 0x0155);
+}
+
+#[test]
+fn immediate_operands_are_read_once_and_skipped() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld sp, $fff0
+                xor a
+                add a, $10      ; c6 10, the 10 on its own would be STOP
+                ld e, a
+                add sp, -2
+                ld hl, sp+4
+                halt
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.register.e, 0x10);
+    assert_eq!(cpu.sp, 0xFFEE);
+    assert_eq!(cpu.register.get_hl(), 0xFFF2);
+}
+
+#[test]
+fn opcode_02_stores_a_at_bc() {
+    // raw bytes, the assembler goes through the same decoder and would agree with a wrong entry
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld bc, $c000
+                ld a, $81
+                db $02              ; ld [bc], a
+                halt
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.bus().read_byte(0xC000), 0x81);
+    assert_eq!(cpu.register.get_bc(), 0xC000);
+}
+
+#[test]
+fn opcode_0f_rotates_a_right_into_carry() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld a, $81
+                db $0f              ; rrca
+                halt
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.register.a, 0xC0);
+    assert!(cpu.register.f.carry && !cpu.register.f.zero);
+}
+
+#[test]
+fn opcode_11_loads_de_and_skips_its_operand() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   db $11, $34, $12    ; ld de, $1234
+                ld b, $42
+                halt
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.register.get_de(), 0x1234);
+    assert_eq!(cpu.register.b, 0x42);
+}
+
+#[test]
+fn opcode_12_stores_a_at_de() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld de, $c001
+                ld a, $5a
+                db $12              ; ld [de], a
+                halt
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.bus().read_byte(0xC001), 0x5A);
+    assert_eq!(cpu.register.get_de(), 0xC001);
+}
+
+#[test]
+fn opcode_af_is_xor_a_not_or_a() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld a, $81
+                scf
+                db $af              ; xor a
+                halt
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.register.a, 0x00);
+    assert!(cpu.register.f.zero && !cpu.register.f.carry);
+}
+
+#[test]
+fn opcodes_34_and_35_step_the_byte_at_hl_not_hl() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld hl, $c000
+                ld [hl], $41
+                db $34              ; inc [hl]
+                db $34              ; inc [hl]
+                db $35              ; dec [hl]
+                halt
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.bus().read_byte(0xC000), 0x42);
+    assert_eq!(cpu.register.get_hl(), 0xC000);
+}
+
+#[test]
+fn opcode_08_stores_sp_at_the_immediate_address() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld sp, $1234
+                db $08, $00, $c0    ; ld [$c000], sp
+                ld b, $42
+                halt
+    ");
+    run_until_halt(&mut cpu, 10);
+    assert_eq!((cpu.bus().read_byte(0xC000), cpu.bus().read_byte(0xC001)), (0x34, 0x12));
+    assert_eq!(cpu.register.b, 0x42);
+}
+
+#[test]
+fn an_illegal_opcode_locks_up_the_cpu_until_restart() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld a, $12
+                db $d3
+                ld a, $34
+                halt
+    ");
+    assert_eq!((cpu.step(), cpu.step()), (Ok(16), Ok(8)));
+    assert_eq!(cpu.step(), Err(CpuError::IllegalInstruction { opcode: 0xD3, pc: 0x0152, bank: 0 }));
+    assert!(cpu.is_locked_up());
+    for _ in 0..10 {
+        assert_eq!(cpu.step(), Ok(4));
+    }
+    assert_eq!((cpu.pc, cpu.register.a), (0x0152, 0x12));
+    cpu.restart();
+    assert!(!cpu.is_locked_up());
+    assert_eq!(cpu.pc, 0x0100);
+}
+
+#[test]
+fn alu_instructions_on_memory_and_a_use_the_right_operation() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld hl, $c000
+                ld [hl], $0f
+                ld a, $3c
+                and a, [hl]
+                ld b, a
+                ld a, $3c
+                sub a, [hl]
+                ld c, a
+                sub a, a
+                ld d, a
+                halt
+    ");
+    run_until_halt(&mut cpu, 20);
+    assert_eq!((cpu.register.b, cpu.register.c, cpu.register.d), (0x0C, 0x2D, 0x00));
+    assert!(cpu.register.f.zero && cpu.register.f.subtract && !cpu.register.f.carry);
+}
+
+#[test]
+fn pop_af_keeps_only_the_flag_bits_and_daa_adjusts_bcd() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld sp, $fffe
+                ld de, $12ff
+                push de
+                pop af
+                ld b, a
+                ld a, $19
+                add a, $28
+                daa
+                halt
+    ");
+    run_until_halt(&mut cpu, 20);
+    assert_eq!(cpu.register.b, 0x12);
+    assert_eq!(cpu.register.a, 0x47);
+    assert_eq!(u8::from(cpu.register.f), 0x00);
+}
+
+#[test]
+fn code_rewritten_in_wram_runs_as_rewritten() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld sp, $fffe
+                ld hl, $c100
+                ld [hl], $3e    ; ld a, n8
+                inc hl
+                ld [hl], $11
+                inc hl
+                ld [hl], $c9    ; ret
+                call $c100
+                ld b, a
+                ld a, $22
+                ld [$c101], a   ; rewrite the operand in place
+                call $c100
+                ld c, a
+                ld a, $33
+                ld [$e101], a   ; and again through echo RAM
+                call $c100
+                ld d, a
+                halt
+    ");
+    run_until_halt(&mut cpu, 50);
+    assert_eq!((cpu.register.b, cpu.register.c, cpu.register.d), (0x11, 0x22, 0x33));
+}
+
+#[test]
+fn code_at_the_same_address_in_another_bank_is_decoded_again() {
+    let mut rom = assemble_rom("
+                jp main
+        org $150
+        main:   ld sp, $fffe
+                ld a, 2
+                ld [$2000], a
+                call $4000
+                ld b, a
+                ld a, 3
+                ld [$2000], a
+                call $4000
+                ld c, a
+                halt
+    ").unwrap();
+    rom[0x0147] = 0x01; // MBC1
+    rom[0x0148] = 0x01; // 64KiB, 4 banks
+    rom.resize(0x10000, 0);
+    rom[0x8000..0x8003].copy_from_slice(&[0x3E, 0x02, 0xC9]); // ld a, 2; ret
+    rom[0xC000..0xC003].copy_from_slice(&[0x3E, 0x03, 0xC9]); // ld a, 3; ret
+    let mut cpu = CPU::new(MemoryBus::new(Cartridge::from_rom(rom).unwrap()), Model::DMG);
+    run_until_halt(&mut cpu, 50);
+    assert_eq!((cpu.register.b, cpu.register.c), (0x02, 0x03));
+}
+
+#[test]
+fn unmapping_the_boot_rom_runs_the_cartridge_underneath() {
+    let mut cpu = machine("
+        org $0
+                ld c, $22
+                halt
+        org $fe
+                rst $00         ; straight after the boot ROM unmaps itself
+                halt
+    ");
+    let mut boot_rom = vec![0; 256];
+    // ld sp, $fffe; ld a, $11; ld b, a; jp $00fa
+    boot_rom[..9].copy_from_slice(&[0x31, 0xFE, 0xFF, 0x3E, 0x11, 0x47, 0xC3, 0xFA, 0x00]);
+    // ld a, 1; ldh [$50], a
+    boot_rom[0xFA..0xFE].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+    cpu.bus_mut().set_boot_rom(boot_rom).unwrap();
+    cpu.restart();
+    run_until_halt(&mut cpu, 20);
+    assert_eq!((cpu.register.b, cpu.register.c), (0x11, 0x22));
+}
+
+/// Not a check, run with `cargo test --release -- --ignored --nocapture headless_speed` to see how
+/// far ahead of real time a headless run gets with the LCD, the timer and its interrupt all going
+#[test]
+#[ignore = "a benchmark"]
+fn headless_speed() {
+    const SOURCE: &str = "
+                jp main
+        org $50
+                inc d
+                reti
+        org $150
+        main:   ld a, %100      ; timer interrupt only
+                ldh [$ffff], a
+                ld a, %101      ; a tick every 16 cycles, an overflow every 4096
+                ldh [$ff07], a
+                ei
+        again:  ld hl, $c000
+                ld b, 0
+        fill:   ld a, b
+                ld [hli], a
+                add a, c
+                ld c, a
+                dec b
+                jr nz, fill
+                jr again
+    ";
+    let emulated_seconds = 20;
+    let mut cpu = machine(SOURCE);
+    let started = std::time::Instant::now();
+    while cpu.bus().cycles() < emulated_seconds * crate::test_roms::CPU_CLOCK_HZ {
+        cpu.step().unwrap();
+    }
+    let elapsed = started.elapsed();
+    println!(
+        "{}s emulated in {:?}, {:.1}x real time",
+        emulated_seconds,
+        elapsed,
+        emulated_seconds as f64 / elapsed.as_secs_f64()
+    );
+}
+
+#[test]
+fn timer_interrupt_runs_its_handler() {
+    let mut cpu = machine("
+                jp main
+        org $50
+                ld b, $99
+                reti
+        org $150
+        main:   ld a, %100      ; timer interrupt only
+                ldh [$ffff], a
+                xor a
+                ldh [$ff0f], a
+                ld a, $fe
+                ldh [$ff05], a  ; overflows after two ticks
+                ld a, %101      ; started, a tick every 16 cycles
+                ldh [$ff07], a
+                ei
+                halt
+                ld c, b         ; only reached once the handler returns
+                halt
+    ");
+    run_until_halt(&mut cpu, 100);
+    for _ in 0..100 {
+        if cpu.register.c == 0x99 {
+            break;
+        }
+        cpu.step().unwrap();
+    }
+    assert_eq!((cpu.register.b, cpu.register.c), (0x99, 0x99));
+}
+
+#[test]
+fn clearing_the_start_bit_abandons_a_serial_transfer() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld a, $42
+                ldh [$ff01], a
+                ld a, $81       ; start on the internal clock
+                ldh [$ff02], a
+                ld a, $01       ; and stop again straight away
+                ldh [$ff02], a
+                ld b, 0
+        wait:   nop             ; 256 * 24 cycles, longer than a whole byte takes
+                nop
+                dec b
+                jr nz, wait
+                halt
+    ");
+    run_until_halt(&mut cpu, 2000);
+    assert_eq!(cpu.bus().interrupts().flag & InterruptSource::Serial.bit(), 0);
+    assert_eq!(cpu.bus().serial.data, 0x42);
+}
+
+#[test]
+fn stat_keeps_its_status_bits_and_interrupts_on_the_selected_mode() {
+    let mut cpu = machine("
+                jp main
+        org $48
+                ld b, $99
+                ldh a, [$ff41]
+                ld e, a
+                reti
+        org $150
+        main:   ld a, %010      ; LCD STAT interrupt only
+                ldh [$ffff], a
+                xor a
+                ldh [$ff0f], a
+                ld a, %1000     ; on entering HBlank
+                ldh [$ff41], a
+                ldh a, [$ff41]
+                ld c, a
+                ei
+                halt
+                ld d, $11       ; only reached once the handler returns
+                halt
+    ");
+    run_until_halt(&mut cpu, 100);
+    for _ in 0..300 {
+        if cpu.register.d == 0x11 {
+            break;
+        }
+        cpu.step().unwrap();
+    }
+    // the select bit as written, LY=LYC (both 0) and the LCD still in VBlank, on line 153 where LY
+    // already reads 0
+    assert_eq!(cpu.register.c, 0b1000_1101);
+    assert_eq!((cpu.register.b, cpu.register.d), (0x99, 0x11));
+    assert_eq!(cpu.register.e & 0b11, 0);
+}
+
+#[test]
+fn halt_wakes_up_without_ime_and_skips_the_handler() {
+    let mut cpu = machine("
+                jp main
+        org $50
+                ld b, $99
+                reti
+        org $150
+        main:   di
+                ld a, %100
+                ldh [$ffff], a
+                ld a, $ff
+                ldh [$ff05], a
+                ld a, %101
+                ldh [$ff07], a
+                halt
+                ld c, $11
+                halt
+    ");
+    run_until_halt(&mut cpu, 100);
+    for _ in 0..100 {
+        if cpu.register.c == 0x11 {
+            break;
+        }
+        cpu.step().unwrap();
+    }
+    assert_eq!((cpu.register.b, cpu.register.c), (0x00, 0x11));
+}
+
+#[test]
+fn halt_bug_reads_a_prefix_twice() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld a, %100      ; a timer interrupt pending with IME off
+                ldh [$ffff], a
+                ldh [$ff0f], a
+                ld e, $00
+                ld a, $f0
+                halt
+                swap a          ; runs as cb cb (set 1, e) and then 37 (scf)
+    ");
+    for _ in 0..9 {
+        cpu.step().unwrap();
+    }
+    assert_eq!((cpu.register.a, cpu.register.e), (0xF0, 0x02));
+    assert!(cpu.register.f.carry);
+    assert_eq!(cpu.pc, 0x015D);
+}
+
+const STOP_SOURCE: &str = "
+                jp main
+        org $150
+        main:   ld a, $ff       ; every shade the darkest, so drawn lines stand out from a blank screen
+                ldh [$ff47], a
+                ld a, $20       ; directions selected
+                ldh [$ff00], a
+                stop
+                ldh a, [$ff04]
+                ld b, a
+                halt
+";
+const AFTER_STOP: u16 = 0x015A;
+// the lightest and darkest shades as they end up in the canvas
+const BLANK: [u8; 4] = [155, 188, 15, 255];
+const DARKEST: [u8; 4] = [15, 56, 15, 255];
+
+/// Runs `cpu` until it executes a STOP
+fn run_until_stopped(cpu: &mut CPU) {
+    for _ in 0..20 {
+        cpu.step().unwrap();
+        if cpu.is_stopped {
+            return;
+        }
+    }
+    panic!("still running at {:#06x}", cpu.pc);
+}
+
+#[test]
+fn stop_skips_its_padding_byte_and_waits_while_time_passes() {
+    let mut cpu = machine(STOP_SOURCE);
+    run_until_stopped(&mut cpu);
+    assert_eq!(cpu.pc, AFTER_STOP);
+    let cycles = cpu.bus().cycles();
+    for _ in 0..100 {
+        assert_eq!(cpu.step(), Ok(4));
+    }
+    assert!(cpu.is_stopped);
+    assert_eq!(cpu.pc, AFTER_STOP);
+    assert_eq!(cpu.bus().cycles(), cycles + 400);
+}
+
+#[test]
+fn stop_resets_div_and_holds_it_there() {
+    let mut cpu = machine(STOP_SOURCE);
+    assert_ne!(cpu.bus().read_byte(0xFF04), 0);
+    run_until_stopped(&mut cpu);
+    assert_eq!(cpu.bus().read_byte(0xFF04), 0);
+    for _ in 0..1000 {
+        cpu.step().unwrap();
+    }
+    cpu.bus_mut().press_key(Key::Down);
+    run_until_halt(&mut cpu, 10);
+    // read a couple of cycles after waking, as if it had never counted while stopped
+    assert_eq!(cpu.register.b, 0);
+}
+
+#[test]
+fn stop_blanks_the_lcd_until_woken() {
+    let mut cpu = machine(STOP_SOURCE);
+    run_until_stopped(&mut cpu);
+    let frames = cpu.bus().frames();
+    while cpu.bus().frames() < frames + 2 {
+        cpu.step().unwrap();
+    }
+    assert!(cpu.bus().gpu.canvas_buffer.chunks_exact(4).all(|pixel| pixel == BLANK));
+
+    cpu.bus_mut().press_key(Key::Down);
+    run_until_halt(&mut cpu, 10);
+    let frames = cpu.bus().frames();
+    while cpu.bus().frames() < frames + 1 {
+        cpu.step().unwrap();
+    }
+    assert!(cpu.bus().gpu.canvas_buffer.chunks_exact(4).all(|pixel| pixel == DARKEST));
+}
+
+#[test]
+fn stop_only_wakes_when_a_selected_line_goes_low() {
+    let mut cpu = machine(STOP_SOURCE);
+    // already held when STOP runs, so it never goes from high to low
+    cpu.bus_mut().press_key(Key::Down);
+    run_until_stopped(&mut cpu);
+    for _ in 0..100 {
+        cpu.step().unwrap();
+    }
+    assert!(cpu.is_stopped);
+
+    // the buttons row isn't selected
+    cpu.bus_mut().press_key(Key::A);
+    for _ in 0..100 {
+        cpu.step().unwrap();
+    }
+    assert!(cpu.is_stopped);
+
+    cpu.bus_mut().release_key(Key::Down);
+    cpu.bus_mut().press_key(Key::Down);
+    run_until_halt(&mut cpu, 10);
+    assert!(!cpu.is_stopped);
+    assert_eq!(cpu.pc, AFTER_STOP + 4);
+}
+
+#[test]
+fn restarting_into_a_boot_rom_powers_the_io_registers_back_on() {
+    let mut cpu = machine("halt");
+    let mut boot_rom = vec![0; 256];
+    boot_rom[0] = 0x31; // ld sp, d16
+    let bus = cpu.bus_mut();
+    bus.set_boot_rom(boot_rom).unwrap();
+    bus.gpu.lcd.scroll_x = 0x12;
+    bus.timer.tma = 0x34;
+    bus.interrupts.enable = 0x1F;
+    cpu.restart();
+
+    assert_eq!(cpu.pc, 0x0000);
+    let bus = cpu.bus();
+    assert_eq!(bus.read_byte(0x0000), 0x31);
+    assert_eq!(bus.read_byte(0xFF40), 0x00); // the LCD stays off until the boot ROM turns it on
+    assert_eq!(bus.read_byte(0xFF43), 0x00);
+    assert_eq!(bus.read_byte(0xFF06), 0x00);
+    assert_eq!(bus.read_byte(0xFF0F), 0xE0);
+    assert_eq!(bus.read_byte(0xFFFF), 0x00);
+}
+
+#[test]
+fn state_round_trips_through_the_cpu() {
+    let mut cpu = machine("halt");
+    let mut state = cpu.state();
+    state.set_af(0x12FF);
+    state.set_bc(0x3456);
+    state.set_hl(0x789A);
+    state.set_pc(0x0150);
+    state.set_ime(true);
+    cpu.set_state(&state);
+
+    let state = cpu.state();
+    assert_eq!(state.af(), 0x12F0);
+    assert_eq!((state.b(), state.c()), (0x34, 0x56));
+    assert!(state.zero() && state.carry());
+    assert_eq!(state.hl(), 0x789A);
+    assert_eq!(state.pc(), 0x0150);
+    assert!(state.ime());
+}
+
+#[test]
+fn inc_hl_into_oam_during_mode_2_corrupts_the_row_being_read() {
+    for model in [Model::DMG, Model::CGB] {
+        let rom = assemble_rom("inc hl").unwrap();
+        let mut cpu = CPU::new(MemoryBus::new(Cartridge::from_rom(rom).unwrap()), model);
+        let mut state = cpu.state();
+        state.set_hl(0xFE00);
+        cpu.set_state(&state);
+        // turning the LCD off and on again puts it at the top of mode 2
+        cpu.bus_mut().write_byte(0xFF40, 0x11);
+        cpu.bus_mut().write_byte(0xFF40, 0x91);
+        let gpu = &mut cpu.bus_mut().gpu;
+        for index in 0..160 {
+            gpu.write_oam(index, (index as u8).wrapping_mul(37));
+        }
+        let before = gpu.oam;
+
+        // the opcode fetch and the incrementer cycle leave it reading row 2
+        cpu.step().unwrap();
+        let word = |row: usize, word: usize| u16::from_le_bytes([before[row * 8 + word * 2], before[row * 8 + word * 2 + 1]]);
+        let (a, b, c) = (word(2, 0), word(1, 0), word(1, 2));
+        let mut expected = before;
+        if model == Model::DMG {
+            expected[16..18].copy_from_slice(&(((a ^ c) & (b ^ c)) ^ c).to_le_bytes());
+            expected.copy_within(10..16, 18);
+        }
+        assert_eq!(cpu.bus().gpu.oam, expected, "{:?}", model);
+    }
+}
+
+#[test]
+fn reading_oam_during_mode_2_corrupts_the_row_being_read() {
+    for model in [Model::DMG, Model::CGB] {
+        let rom = assemble_rom("ld a, [hl]").unwrap();
+        let mut cpu = CPU::new(MemoryBus::new(Cartridge::from_rom(rom).unwrap()), model);
+        let mut state = cpu.state();
+        state.set_hl(0xFE00);
+        cpu.set_state(&state);
+        cpu.bus_mut().write_byte(0xFF40, 0x11);
+        cpu.bus_mut().write_byte(0xFF40, 0x91);
+        let gpu = &mut cpu.bus_mut().gpu;
+        for index in 0..160 {
+            gpu.write_oam(index, (index as u8).wrapping_mul(37));
+        }
+        let before = gpu.oam;
+
+        // the opcode fetch and the read leave it reading row 2
+        cpu.step().unwrap();
+        let word = |row: usize, word: usize| u16::from_le_bytes([before[row * 8 + word * 2], before[row * 8 + word * 2 + 1]]);
+        let (a, b, c) = (word(2, 0), word(1, 0), word(1, 2));
+        let mut expected = before;
+        if model == Model::DMG {
+            expected[16..18].copy_from_slice(&(b | (a & c)).to_le_bytes());
+            expected.copy_within(10..16, 18);
+        }
+        assert_eq!(cpu.bus().gpu.oam, expected, "{:?}", model);
+    }
+}
+
+/// A trace sink the test can still read after handing it to the CPU
+#[derive(Clone, Default)]
+struct SharedLog(Rc<RefCell<Vec<u8>>>);
+
+impl std::io::Write for SharedLog {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(bytes)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn trace_lines_match_gameboy_doctor() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld bc, $1234
+                ld de, $5678
+                ld hl, $9abc
+                ld sp, $dff0
+                ld a, $5a
+                scf
+                nop
+                halt
+    ");
+    for _ in 0..7 {
+        cpu.step().unwrap();
+    }
+    let log = SharedLog::default();
+    cpu.set_trace(Some(Box::new(log.clone())));
+    cpu.step().unwrap();
+    assert_eq!(
+        String::from_utf8(log.0.borrow().clone()).unwrap(),
+        "A:5A F:90 B:12 C:34 D:56 E:78 H:9A L:BC SP:DFF0 PC:015F PCMEM:00,76,00,00\n"
+    );
+}
+
+#[test]
+fn hooks_see_steps_and_memory_accesses() {
+
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld a, $42
+                ld [$c000], a
+                halt
+    ");
+    let steps = Rc::new(RefCell::new(Vec::new()));
+    let writes = Rc::new(RefCell::new(Vec::new()));
+    let (steps_seen, writes_seen) = (steps.clone(), writes.clone());
+    cpu.set_hooks(Some(
+        Hooks::new()
+            .on_before_step(move |state| steps_seen.borrow_mut().push(state.pc()))
+            .on_write(move |address, value| writes_seen.borrow_mut().push((address, value))),
+    ));
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(*steps.borrow(), [0x0100, 0x0150, 0x0152, 0x0155]);
+    assert_eq!(*writes.borrow(), [(0xC000, 0x42)]);
+}
+
+#[test]
+fn read_hook_sees_every_fetch_and_data_read() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld a, [$c010]
+                halt
+    ");
+    cpu.bus_mut().write_byte(0xC010, 0x77);
+    let reads = Rc::new(RefCell::new(Vec::new()));
+    let reads_seen = reads.clone();
+    cpu.set_hooks(Some(Hooks::new().on_read(move |address, value| reads_seen.borrow_mut().push((address, value)))));
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(reads.borrow()[..8], [
+        (0x0100, 0xC3), (0x0101, 0x50), (0x0102, 0x01),
+        (0x0150, 0xFA), (0x0151, 0x10), (0x0152, 0xC0), (0xC010, 0x77),
+        (0x0153, 0x76),
+    ]);
+}
+
+#[test]
+fn interrupt_and_after_step_hooks_see_the_dispatch() {
+    let mut cpu = machine("
+                jp main
+        org $50
+                reti
+        org $150
+        main:   ld a, %100
+                ldh [$ffff], a
+                ldh [$ff0f], a  ; a timer interrupt already pending
+                ei
+                nop
+                halt
+    ");
+    let interrupts = Rc::new(RefCell::new(Vec::new()));
+    let steps = Rc::new(RefCell::new(Vec::new()));
+    let (interrupts_seen, steps_seen) = (interrupts.clone(), steps.clone());
+    cpu.set_hooks(Some(
+        Hooks::new()
+            .on_interrupt(move |source| interrupts_seen.borrow_mut().push(source))
+            .on_after_step(move |state, cycles| steps_seen.borrow_mut().push((state.pc(), cycles))),
+    ));
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(*interrupts.borrow(), [InterruptSource::Timer]);
+    // ei takes effect after the nop, the dispatch is a step of its own that lands on the vector
+    assert_eq!(steps.borrow()[4..], [(0x0157, 4), (0x0158, 4), (0x0050, 20), (0x0158, 16), (0x0159, 4)]);
+    assert_eq!(steps.borrow().iter().map(|&(_, cycles)| cycles as u64).sum::<u64>(), cpu.bus().cycles());
+}
+
+#[test]
+fn bank_switch_hook_sees_mbc1_bank_selects() {
+    let mut rom = assemble_rom("
+                jp main
+        org $150
+        main:   ld a, 2
+                ld [$2000], a
+                ld a, [$4000]
+                ld b, a
+                ld a, 2         ; the same bank again isn't a switch
+                ld [$2000], a
+                halt
+    ").unwrap();
+    rom[0x0147] = 0x01; // MBC1
+    rom[0x0148] = 0x01; // 64KiB, 4 banks
+    rom.resize(0x10000, 0);
+    rom[0x8000] = 0x5B; // the start of bank 2
+    let mut cpu = CPU::new(MemoryBus::new(Cartridge::from_rom(rom).unwrap()), Model::DMG);
+    let switches = Rc::new(RefCell::new(Vec::new()));
+    let switches_seen = switches.clone();
+    cpu.set_hooks(Some(Hooks::new().on_bank_switch(move |old, new| switches_seen.borrow_mut().push((old, new)))));
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(*switches.borrow(), [(1, 2)]);
+    assert_eq!(cpu.register.b, 0x5B);
+}
+
+#[test]
+fn call_stack_follows_calls_and_unwinds_when_sp_is_reset() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   ld sp, $fffe
+                call outer
+                halt
+        outer:  call inner
+                ret
+        inner:  ld sp, $fffc    ; return straight to main, skipping outer
+                ret
+    ");
+    for _ in 0..4 {
+        cpu.step().unwrap();
+    }
+    let frames: Vec<_> = cpu.call_stack().frames().iter().map(|frame| (frame.caller, frame.target, frame.sp)).collect();
+    assert_eq!(frames, [(0x0153, 0x0157, 0xFFFC), (0x0157, 0x015B, 0xFFFA)]);
+
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.pc, 0x0157);
+    assert!(cpu.call_stack().frames().is_empty());
+    assert_eq!(cpu.call_stack().mismatches(), 1);
+}
+
+#[test]
+fn bus_counts_cycles_and_frames() {
+    let mut cpu = machine("
+                jp main
+        org $150
+        main:   jr main
+    ");
+    let mut total = 0;
+    while cpu.bus().frames() < 2 {
+        total += cpu.step().unwrap() as u64;
+    }
+    assert_eq!(cpu.bus().cycles(), total);
+    // a frame is 70224 cycles, the first VBlank comes 144 lines after the LCD starts at line 0
+    assert!(total > 70224 && total <= 2 * 70224, "{}", total);
+}
+
+/// Plain RAM shared with the test, so it can look at what the program left behind
+struct FlatMemory(Rc<RefCell<Vec<u8>>>);
+
+impl BusDevice for FlatMemory {
+    fn read(&self, address: u16) -> u8 {
+        self.0.borrow()[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.0.borrow_mut()[address as usize] = value;
+    }
+}
+
+#[test]
+fn a_device_mapped_over_everything_replaces_the_hardware() {
+    let rom = assemble_rom("
+                jp main
+        org $150
+        main:   ld a, $42
+                ld [$8000], a   ; VRAM
+                ld [$ff40], a   ; LCDC
+                ld [$2000], a   ; an MBC bank select on a cartridge
+                ld a, [$2000]
+                ld b, a
+                halt
+    ").unwrap();
+    let memory = Rc::new(RefCell::new(vec![0; 0x10000]));
+    memory.borrow_mut()[..rom.len()].copy_from_slice(&rom);
+    let mut cpu = machine("");
+    cpu.bus_mut().map_device(0x0000..=0xFFFF, FlatMemory(memory.clone()));
+
+    run_until_halt(&mut cpu, 10);
+    assert_eq!(cpu.register.b, 0x42);
+    assert_eq!(memory.borrow()[0x8000], 0x42);
+    assert_eq!(memory.borrow()[0xFF40], 0x42);
+    assert_eq!(cpu.bus().gpu.vram[0], 0x00);
+    assert_eq!(cpu.bus().gpu.lcd.control.raw, 0x91);
+}
+
+/// Checks a fresh machine against the post-boot tables in Pan Docs, `io` only lists the registers it
+/// documents for `model`
+fn assert_post_boot(model: Model, (af, bc, de, hl): (u16, u16, u16, u16), io: &[(u16, u8)]) {
+    let mut rom = assemble_rom("nop").unwrap();
+    rom[0x014D] = 0xE7; // a valid checksum for the all zero header, the DMG and MGB flags depend on it
+    let cpu = CPU::new(MemoryBus::new(Cartridge::from_rom(rom).unwrap()), model);
+    let registers = (cpu.register.get_af(), cpu.register.get_bc(), cpu.register.get_de(), cpu.register.get_hl());
+    assert_eq!(registers, (af, bc, de, hl), "{:?}", model);
+    assert_eq!((cpu.sp, cpu.pc), (0xFFFE, 0x0100), "{:?}", model);
+    for &(address, value) in io {
+        assert_eq!(cpu.bus().read_byte(address), value, "{:?} {:#06x}", model, address);
+    }
+}
+
+#[test]
+fn dmg0_post_boot_state() {
+    assert_post_boot(Model::DMG0, (0x0100, 0xFF13, 0x00C1, 0x8403), &[(0xFF40, 0x91), (0xFF41, 0x81), (0xFF44, 0x91), (0xFF04, 0x18)]);
+}
+
+#[test]
+fn dmg_post_boot_state() {
+    assert_post_boot(Model::DMG, (0x01B0, 0x0013, 0x00D8, 0x014D), &[(0xFF40, 0x91), (0xFF41, 0x85), (0xFF44, 0x00), (0xFF04, 0xAB)]);
+}
+
+#[test]
+fn mgb_post_boot_state() {
+    assert_post_boot(Model::MGB, (0xFFB0, 0x0013, 0x00D8, 0x014D), &[(0xFF40, 0x91), (0xFF41, 0x85), (0xFF44, 0x00), (0xFF04, 0xAB)]);
+}
+
+// Pan Docs leaves STAT, LY and DIV open on the SGB and CGB models
+
+#[test]
+fn sgb_post_boot_state() {
+    assert_post_boot(Model::SGB, (0x0100, 0x0014, 0x0000, 0xC060), &[(0xFF40, 0x91), (0xFF02, 0x7E)]);
+}
+
+#[test]
+fn sgb2_post_boot_state() {
+    assert_post_boot(Model::SGB2, (0xFF00, 0x0014, 0x0000, 0xC060), &[(0xFF40, 0x91), (0xFF02, 0x7E)]);
+}
+
+#[test]
+fn cgb_post_boot_state() {
+    assert_post_boot(Model::CGB, (0x1180, 0x0000, 0xFF56, 0x000D), &[(0xFF40, 0x91), (0xFF02, 0x7F)]);
+}
+
+#[test]
+fn agb_post_boot_state() {
+    assert_post_boot(Model::AGB, (0x1100, 0x0100, 0xFF56, 0x000D), &[(0xFF40, 0x91), (0xFF02, 0x7F)]);
+}
@@ -0,0 +1,75 @@
+//! Peripherals that plug into `MemoryBus` at an address range instead of being wired into its
+//! address decoding.
+//!
+//! This is an overlay for new hardware, debugging aids and tests. The built in hardware (GPU,
+//! timer, serial port, joypad, DMA) is still decoded by the bus itself and doesn't go through this
+//! trait, since the bus schedules its events and test ROM runners read its state directly.
+//!
+//! A mapped device sees every access in its range before the built in hardware does, the one
+//! exception being IF ($FF0F) and IE ($FFFF), which always belong to the interrupt controller the
+//! CPU checks directly. Mapping one over the whole address space turns the bus into plain memory.
+use std::ops::RangeInclusive;
+use crate::interrupts::InterruptController;
+
+/// Something that answers the reads and writes for the addresses it's mapped at. Addresses are
+/// passed through as the CPU put them on the bus, not relative to the start of the range
+pub trait BusDevice {
+    fn read(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    /// Advances the device by `cycles` T-cycles along with the rest of the bus
+    fn tick(&mut self, _cycles: u8, _interrupts: &mut InterruptController) {}
+}
+
+const PAGE_COUNT: usize = 0x100;
+
+pub struct Devices {
+    mapped: Vec<(RangeInclusive<u16>, Box<dyn BusDevice>)>,
+    pages: [bool; PAGE_COUNT], // whether any device starts, ends or lies within each 256 byte page
+}
+
+impl Default for Devices {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Devices {
+    pub fn new() -> Self {
+        Devices {
+            mapped: Vec::new(),
+            pages: [false; PAGE_COUNT],
+        }
+    }
+
+    /// Devices mapped later sit on top of earlier ones where their ranges overlap
+    pub fn map(&mut self, range: RangeInclusive<u16>, device: Box<dyn BusDevice>) {
+        for page in (*range.start() >> 8)..=(*range.end() >> 8) {
+            self.pages[page as usize] = true;
+        }
+        self.mapped.push((range, device));
+    }
+
+    pub fn get(&self, address: u16) -> Option<&dyn BusDevice> {
+        let index = self.index_of(address)?;
+        Some(self.mapped[index].1.as_ref())
+    }
+
+    pub fn get_mut(&mut self, address: u16) -> Option<&mut dyn BusDevice> {
+        let index = self.index_of(address)?;
+        Some(self.mapped[index].1.as_mut())
+    }
+
+    pub fn tick(&mut self, cycles: u8, interrupts: &mut InterruptController) {
+        for (_, device) in &mut self.mapped {
+            device.tick(cycles, interrupts);
+        }
+    }
+
+    fn index_of(&self, address: u16) -> Option<usize> {
+        // the page check keeps the common case, nothing mapped nearby, to a single load
+        if !self.pages[(address >> 8) as usize] || address == 0xFF0F || address == 0xFFFF {
+            return None;
+        }
+        self.mapped.iter().rposition(|(range, _)| range.contains(&address))
+    }
+}
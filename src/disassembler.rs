@@ -0,0 +1,390 @@
+use std::fmt;
+use crate::bus::Bus;
+use crate::instruction::{Instruction, ADDHLTarget, ArithmeticTarget, IncTarget, ByteAddressFromA, AFromByteAddress, IndirectFromA, AFromIndirect, WordByteSource, WordByteTarget, PrefixTarget, JumpTest, LoadType, LoadByteTarget, LoadByteSource, StackTarget};
+
+/// One decoded instruction, rendered in RGBDS syntax
+pub struct DisassembledLine {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+impl fmt::Display for DisassembledLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        write!(f, "${:04x}  {:<8}  {}", self.address, bytes.join(" "), self.text)
+    }
+}
+
+/// Disassembles `bytes` as if they were mapped starting at `origin`
+pub fn disassemble(bytes: &[u8], origin: u16) -> Vec<DisassembledLine> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let line = disassemble_one(&bytes[offset..], origin.wrapping_add(offset as u16));
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+/// Disassembles every instruction that starts within `start..=end` on the bus
+pub fn disassemble_range<B: Bus>(bus: &B, start: u16, end: u16) -> Vec<DisassembledLine> {
+    let mut lines = Vec::new();
+    let mut address = start;
+    while address <= end {
+        // an instruction is at most 3 bytes long
+        let bytes: Vec<u8> = (0..3).map(|i| bus.read_byte(address.wrapping_add(i))).collect();
+        let line = disassemble_one(&bytes, address);
+        let (next, overflowed) = address.overflowing_add(line.bytes.len() as u16);
+        lines.push(line);
+        if overflowed {
+            break;
+        }
+        address = next;
+    }
+    lines
+}
+
+/// Decodes the instruction at the start of `bytes`, falling back to a `db` when it can't be decoded
+pub fn disassemble_one(bytes: &[u8], address: u16) -> DisassembledLine {
+    let prefixed = bytes[0] == 0xCB;
+    let decoded = if prefixed {
+        bytes.get(1).and_then(|&byte| Instruction::from_byte(byte, true))
+    } else {
+        Instruction::from_byte(bytes[0], false)
+    };
+    match decoded {
+        Some(instruction) if bytes.len() >= instruction.length() as usize => {
+            let length = instruction.length() as usize;
+            let operands = Operands::Resolved { address, bytes: &bytes[..length] };
+            DisassembledLine {
+                address,
+                bytes: bytes[..length].to_vec(),
+                text: render(&instruction, &operands),
+            }
+        }
+        _ => DisassembledLine {
+            address,
+            bytes: vec![bytes[0]],
+            text: format!("db ${:02x}", bytes[0]),
+        },
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&render(self, &Operands::Placeholder))
+    }
+}
+
+/// Where immediate operands come from, `Placeholder` renders the RGBDS operand names instead
+enum Operands<'a> {
+    Placeholder,
+    Resolved { address: u16, bytes: &'a [u8] },
+}
+
+impl Operands<'_> {
+    fn n8(&self) -> String {
+        match self {
+            Operands::Placeholder => "n8".to_string(),
+            Operands::Resolved { bytes, .. } => format!("${:02x}", bytes[1]),
+        }
+    }
+
+    fn n16(&self) -> String {
+        match self {
+            Operands::Placeholder => "n16".to_string(),
+            Operands::Resolved { bytes, .. } => format!("${:04x}", u16::from_le_bytes([bytes[1], bytes[2]])),
+        }
+    }
+
+    /// LDH only takes the low byte, the address is always in $FF00-$FFFF
+    fn high_page(&self) -> String {
+        match self {
+            Operands::Placeholder => "n16".to_string(),
+            Operands::Resolved { bytes, .. } => format!("${:04x}", 0xFF00 | bytes[1] as u16),
+        }
+    }
+
+    /// JR offsets are resolved to the absolute address they land on
+    fn relative_target(&self) -> String {
+        match self {
+            Operands::Placeholder => "e8".to_string(),
+            Operands::Resolved { address, bytes } => {
+                let target = address.wrapping_add(2).wrapping_add(bytes[1] as i8 as u16);
+                format!("${:04x}", target)
+            }
+        }
+    }
+
+    /// Signed offset with an explicit sign, so `sp` plus it reads naturally
+    fn signed_offset(&self) -> String {
+        match self {
+            Operands::Placeholder => "+e8".to_string(),
+            Operands::Resolved { bytes, .. } => {
+                let offset = bytes[1] as i8;
+                let sign = if offset < 0 { '-' } else { '+' };
+                format!("{}${:02x}", sign, offset.unsigned_abs())
+            }
+        }
+    }
+}
+
+fn render(instruction: &Instruction, operands: &Operands) -> String {
+    match instruction {
+        Instruction::NOP() => "nop".to_string(),
+        Instruction::HALT() => "halt".to_string(),
+        Instruction::STOP() => "stop".to_string(),
+        Instruction::DI() => "di".to_string(),
+        Instruction::EI() => "ei".to_string(),
+        Instruction::CCF() => "ccf".to_string(),
+        Instruction::SCF() => "scf".to_string(),
+        Instruction::CPL() => "cpl".to_string(),
+        Instruction::DAA() => "daa".to_string(),
+        Instruction::RRA() => "rra".to_string(),
+        Instruction::RLA() => "rla".to_string(),
+        Instruction::RRCA() => "rrca".to_string(),
+        Instruction::RRLA() => "rlca".to_string(),
+        Instruction::ILLEGAL(opcode) => format!("db ${:02x}", opcode),
+        Instruction::ADD(target) => format!("add a, {}", arithmetic(target, operands)),
+        Instruction::ADC(target) => format!("adc a, {}", arithmetic(target, operands)),
+        Instruction::SUB(target) => format!("sub a, {}", arithmetic(target, operands)),
+        Instruction::SBC(target) => format!("sbc a, {}", arithmetic(target, operands)),
+        Instruction::AND(target) => format!("and a, {}", arithmetic(target, operands)),
+        Instruction::OR(target) => format!("or a, {}", arithmetic(target, operands)),
+        Instruction::XOR(target) => format!("xor a, {}", arithmetic(target, operands)),
+        Instruction::CP(target) => format!("cp a, {}", arithmetic(target, operands)),
+        Instruction::INC(target) => format!("inc {}", inc_target(target)),
+        Instruction::DEC(target) => format!("dec {}", inc_target(target)),
+        Instruction::AddHL(target) => {
+            let source = match target {
+                ADDHLTarget::BC => "bc",
+                ADDHLTarget::DE => "de",
+                ADDHLTarget::HL => "hl",
+                ADDHLTarget::SP => "sp",
+            };
+            format!("add hl, {}", source)
+        }
+        Instruction::ADDSP() => format!("add sp, {}", operands.signed_offset().trim_start_matches('+')),
+        Instruction::LDHL() => format!("ld hl, sp{}", operands.signed_offset()),
+        Instruction::LDSP() => "ld sp, hl".to_string(),
+        Instruction::BIT(target, bit) => format!("bit {}, {}", bit, prefix_target(target)),
+        Instruction::RESET(target, bit) => format!("res {}, {}", bit, prefix_target(target)),
+        Instruction::SET(target, bit) => format!("set {}, {}", bit, prefix_target(target)),
+        Instruction::SRL(target) => format!("srl {}", prefix_target(target)),
+        Instruction::RR(target) => format!("rr {}", prefix_target(target)),
+        Instruction::RL(target) => format!("rl {}", prefix_target(target)),
+        Instruction::RRC(target) => format!("rrc {}", prefix_target(target)),
+        Instruction::RLC(target) => format!("rlc {}", prefix_target(target)),
+        Instruction::SRA(target) => format!("sra {}", prefix_target(target)),
+        Instruction::SLA(target) => format!("sla {}", prefix_target(target)),
+        Instruction::SWAP(target) => format!("swap {}", prefix_target(target)),
+        Instruction::JP(test) => format!("jp {}{}", condition(test), operands.n16()),
+        Instruction::JPHL() => "jp hl".to_string(),
+        Instruction::JR(test) => format!("jr {}{}", condition(test), operands.relative_target()),
+        Instruction::CALL(test) => format!("call {}{}", condition(test), operands.n16()),
+        Instruction::RET(JumpTest::Always) => "ret".to_string(),
+        Instruction::RET(test) => format!("ret {}", condition(test).trim_end_matches(", ")),
+        Instruction::RETI() => "reti".to_string(),
+        Instruction::RST(vector) => format!("rst ${:02x}", vector),
+        Instruction::PUSH(target) => format!("push {}", stack_target(target)),
+        Instruction::POP(target) => format!("pop {}", stack_target(target)),
+        Instruction::LD(load_type) => load(load_type, operands),
+    }
+}
+
+fn load(load_type: &LoadType, operands: &Operands) -> String {
+    match load_type {
+        LoadType::Byte(target, source) => {
+            let target = match target {
+                LoadByteTarget::A => "a",
+                LoadByteTarget::B => "b",
+                LoadByteTarget::C => "c",
+                LoadByteTarget::D => "d",
+                LoadByteTarget::E => "e",
+                LoadByteTarget::H => "h",
+                LoadByteTarget::L => "l",
+                LoadByteTarget::HLI => "[hl]",
+            };
+            let source = match source {
+                LoadByteSource::A => "a".to_string(),
+                LoadByteSource::B => "b".to_string(),
+                LoadByteSource::C => "c".to_string(),
+                LoadByteSource::D => "d".to_string(),
+                LoadByteSource::E => "e".to_string(),
+                LoadByteSource::H => "h".to_string(),
+                LoadByteSource::L => "l".to_string(),
+                LoadByteSource::HLI => "[hl]".to_string(),
+                LoadByteSource::D8 => operands.n8(),
+            };
+            format!("ld {}, {}", target, source)
+        }
+        LoadType::Word(target, source) => {
+            let target = match target {
+                WordByteTarget::BC => "bc".to_string(),
+                WordByteTarget::DE => "de".to_string(),
+                WordByteTarget::HL => "hl".to_string(),
+                WordByteTarget::SP => "sp".to_string(),
+                WordByteTarget::U16 => format!("[{}]", operands.n16()),
+            };
+            let source = match source {
+                WordByteSource::U16 => operands.n16(),
+                WordByteSource::SP => "sp".to_string(),
+            };
+            format!("ld {}, {}", target, source)
+        }
+        LoadType::AFromIndirect(source) => {
+            let source = match source {
+                AFromIndirect::BC => "[bc]",
+                AFromIndirect::DE => "[de]",
+                AFromIndirect::HLPlus => "[hl+]",
+                AFromIndirect::HLMinus => "[hl-]",
+            };
+            format!("ld a, {}", source)
+        }
+        LoadType::IndirectFromA(target) => {
+            let target = match target {
+                IndirectFromA::BC => "[bc]",
+                IndirectFromA::DE => "[de]",
+                IndirectFromA::HLPlus => "[hl+]",
+                IndirectFromA::HLMinus => "[hl-]",
+            };
+            format!("ld {}, a", target)
+        }
+        LoadType::AFromByteAddress(source) => match source {
+            AFromByteAddress::U16 => format!("ld a, [{}]", operands.n16()),
+            AFromByteAddress::FF00U8 => format!("ldh a, [{}]", operands.high_page()),
+            AFromByteAddress::FFOOC => "ld a, [$ff00+c]".to_string(),
+        },
+        LoadType::ByteAddressFromA(target) => match target {
+            ByteAddressFromA::U16 => format!("ld [{}], a", operands.n16()),
+            ByteAddressFromA::FF00U8 => format!("ldh [{}], a", operands.high_page()),
+            ByteAddressFromA::FFOOC => "ld [$ff00+c], a".to_string(),
+        },
+    }
+}
+
+fn arithmetic(target: &ArithmeticTarget, operands: &Operands) -> String {
+    match target {
+        ArithmeticTarget::A => "a".to_string(),
+        ArithmeticTarget::B => "b".to_string(),
+        ArithmeticTarget::C => "c".to_string(),
+        ArithmeticTarget::D => "d".to_string(),
+        ArithmeticTarget::E => "e".to_string(),
+        ArithmeticTarget::H => "h".to_string(),
+        ArithmeticTarget::L => "l".to_string(),
+        ArithmeticTarget::HL => "[hl]".to_string(),
+        ArithmeticTarget::PC => operands.n8(),
+    }
+}
+
+fn inc_target(target: &IncTarget) -> &'static str {
+    match target {
+        IncTarget::A => "a",
+        IncTarget::B => "b",
+        IncTarget::C => "c",
+        IncTarget::D => "d",
+        IncTarget::E => "e",
+        IncTarget::H => "h",
+        IncTarget::L => "l",
+        IncTarget::HLI => "[hl]",
+        IncTarget::BC => "bc",
+        IncTarget::DE => "de",
+        IncTarget::HL => "hl",
+        IncTarget::SP => "sp",
+    }
+}
+
+fn prefix_target(target: &PrefixTarget) -> &'static str {
+    match target {
+        PrefixTarget::A => "a",
+        PrefixTarget::B => "b",
+        PrefixTarget::C => "c",
+        PrefixTarget::D => "d",
+        PrefixTarget::E => "e",
+        PrefixTarget::H => "h",
+        PrefixTarget::L => "l",
+        PrefixTarget::HL => "[hl]",
+    }
+}
+
+fn stack_target(target: &StackTarget) -> &'static str {
+    match target {
+        StackTarget::AF => "af",
+        StackTarget::BC => "bc",
+        StackTarget::DE => "de",
+        StackTarget::Hl => "hl",
+    }
+}
+
+/// The condition prefix including its trailing separator, empty for unconditional jumps
+fn condition(test: &JumpTest) -> &'static str {
+    match test {
+        JumpTest::NotZero => "nz, ",
+        JumpTest::Zero => "z, ",
+        JumpTest::NotCarry => "nc, ",
+        JumpTest::Carry => "c, ",
+        JumpTest::Always => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    fn round_trip(source: &str, origin: u16) -> Vec<String> {
+        let bytes = assemble(source, origin).unwrap_or_else(|error| panic!("{}", error));
+        disassemble(&bytes, origin).into_iter().map(|line| line.text).collect()
+    }
+
+    #[test]
+    fn resolves_relative_jumps_to_their_targets() {
+        let lines = round_trip("
+            back:   nop
+                    jr back
+                    jr nz, ahead
+                    jr c, back+$80
+                    nop
+            ahead:  halt
+        ", 0xC000);
+        assert_eq!(lines, ["nop", "jr $c000", "jr nz, $c008", "jr c, $c080", "nop", "halt"]);
+    }
+
+    #[test]
+    fn renders_prefixed_instructions_and_immediates() {
+        let lines = round_trip("
+                    swap a
+                    bit 7, [hl]
+                    res 0, b
+                    set 3, l
+                    rl c
+                    ld a, $f0
+                    ld bc, $1234
+                    ldh [$ff44], a
+                    add sp, -2
+                    ld hl, sp+$10
+        ", 0x0150);
+        assert_eq!(lines, [
+            "swap a", "bit 7, [hl]", "res 0, b", "set 3, l", "rl c",
+            "ld a, $f0", "ld bc, $1234", "ldh [$ff44], a", "add sp, -$02", "ld hl, sp+$10",
+        ]);
+    }
+
+    #[test]
+    fn lines_carry_their_address_and_bytes() {
+        let lines = disassemble(&[0xCB, 0x37, 0x18, 0xFE, 0xD3], 0x0200);
+        let rendered: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        assert_eq!(rendered, ["$0200  cb 37     swap a", "$0202  18 fe     jr $0202", "$0204  d3        db $d3"]);
+    }
+
+    #[test]
+    fn instructions_on_their_own_show_operand_placeholders() {
+        let rendered: Vec<String> = [0x20, 0xCD, 0xE0, 0xF8]
+            .into_iter()
+            .map(|opcode| Instruction::from_byte(opcode, false).unwrap().to_string())
+            .collect();
+        assert_eq!(rendered, ["jr nz, e8", "call n16", "ldh [n16], a", "ld hl, sp+e8"]);
+    }
+}
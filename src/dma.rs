@@ -0,0 +1,55 @@
+use crate::bus::OAM_SIZE;
+
+/// OAM DMA copies 160 bytes from XX00-XX9F into OAM, one byte per machine cycle
+pub struct Dma {
+    pub source: u8, // DMA ($FF46), the high byte of the source address
+    index: usize,
+    delay: u8,
+    active: bool,
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Dma {
+            source: 0xFF,
+            index: 0,
+            delay: 0,
+            active: false,
+        }
+    }
+
+    pub fn start(&mut self, value: u8) {
+        self.source = value;
+        self.index = 0;
+        // the first byte is copied one machine cycle after the write
+        self.delay = 1;
+        self.active = true;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active && self.delay == 0
+    }
+
+    /// Advances one machine cycle and returns the (source address, OAM index) pair to copy, if any
+    pub fn step(&mut self) -> Option<(u16, usize)> {
+        if !self.active {
+            return None;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return None;
+        }
+        let transfer = (((self.source as u16) << 8) | self.index as u16, self.index);
+        self.index += 1;
+        if self.index >= OAM_SIZE {
+            self.active = false;
+        }
+        Some(transfer)
+    }
+}
@@ -0,0 +1,104 @@
+//! Callbacks tools can attach to the CPU with `CPU::set_hooks` without touching the core.
+//!
+//! Without hooks installed every call site costs a single `Option` check. Opcode and operand
+//! fetches show up as reads like any other access.
+use crate::cpu::CpuState;
+use crate::interrupts::InterruptSource;
+
+type StepHook = Box<dyn FnMut(&CpuState)>;
+type AfterStepHook = Box<dyn FnMut(&CpuState, u8)>;
+type AccessHook = Box<dyn FnMut(u16, u8)>;
+type InterruptHook = Box<dyn FnMut(InterruptSource)>;
+type BankSwitchHook = Box<dyn FnMut(u16, u16)>;
+
+#[derive(Default)]
+pub struct Hooks {
+    before_step: Option<StepHook>,
+    after_step: Option<AfterStepHook>,
+    read: Option<AccessHook>,
+    write: Option<AccessHook>,
+    interrupt: Option<InterruptHook>,
+    bank_switch: Option<BankSwitchHook>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Hooks::default()
+    }
+
+    /// Called with the CPU state before every `CPU::step`
+    pub fn on_before_step(mut self, hook: impl FnMut(&CpuState) + 'static) -> Self {
+        self.before_step = Some(Box::new(hook));
+        self
+    }
+
+    /// Called with the CPU state and the T-cycles taken after every successful `CPU::step`
+    pub fn on_after_step(mut self, hook: impl FnMut(&CpuState, u8) + 'static) -> Self {
+        self.after_step = Some(Box::new(hook));
+        self
+    }
+
+    /// Called with the address and value of every memory read the CPU makes
+    pub fn on_read(mut self, hook: impl FnMut(u16, u8) + 'static) -> Self {
+        self.read = Some(Box::new(hook));
+        self
+    }
+
+    /// Called with the address and value of every memory write the CPU makes
+    pub fn on_write(mut self, hook: impl FnMut(u16, u8) + 'static) -> Self {
+        self.write = Some(Box::new(hook));
+        self
+    }
+
+    /// Called when an interrupt is dispatched, before jumping to its vector
+    pub fn on_interrupt(mut self, hook: impl FnMut(InterruptSource) + 'static) -> Self {
+        self.interrupt = Some(Box::new(hook));
+        self
+    }
+
+    /// Called with the old and new bank when a write to the MBC changes the ROM bank at 4000-7FFF
+    pub fn on_bank_switch(mut self, hook: impl FnMut(u16, u16) + 'static) -> Self {
+        self.bank_switch = Some(Box::new(hook));
+        self
+    }
+
+    pub(crate) fn wants_steps(&self) -> bool {
+        self.before_step.is_some() || self.after_step.is_some()
+    }
+
+    pub(crate) fn before_step(&mut self, state: &CpuState) {
+        if let Some(hook) = self.before_step.as_mut() {
+            hook(state);
+        }
+    }
+
+    pub(crate) fn after_step(&mut self, state: &CpuState, cycles: u8) {
+        if let Some(hook) = self.after_step.as_mut() {
+            hook(state, cycles);
+        }
+    }
+
+    pub(crate) fn read(&mut self, address: u16, value: u8) {
+        if let Some(hook) = self.read.as_mut() {
+            hook(address, value);
+        }
+    }
+
+    pub(crate) fn write(&mut self, address: u16, value: u8) {
+        if let Some(hook) = self.write.as_mut() {
+            hook(address, value);
+        }
+    }
+
+    pub(crate) fn interrupt(&mut self, source: InterruptSource) {
+        if let Some(hook) = self.interrupt.as_mut() {
+            hook(source);
+        }
+    }
+
+    pub(crate) fn bank_switch(&mut self, old: u16, new: u16) {
+        if let Some(hook) = self.bank_switch.as_mut() {
+            hook(old, new);
+        }
+    }
+}
@@ -10,24 +10,29 @@ impl Instruction {
         match byte {
             0x00 => Some(Instruction::NOP()),
             0x01 => Some(Instruction::LD(LoadType::Word(WordByteTarget::BC, WordByteSource::U16))),
+            0x02 => Some(Instruction::LD(LoadType::IndirectFromA(IndirectFromA::BC))),
             0x03 => Some(Instruction::INC(IncTarget::BC)),
             0x04 => Some(Instruction::INC(IncTarget::B)),
             0x05 => Some(Instruction::DEC(IncTarget::B)),
             0x06 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::D8))),
             0x07 => Some(Instruction::RRLA()),
-            0x08 => Some(Instruction::LD(LoadType::Word(WordByteTarget::U16, WordByteSource::U16))),
+            0x08 => Some(Instruction::LD(LoadType::Word(WordByteTarget::U16, WordByteSource::SP))),
             0x09 => Some(Instruction::AddHL(ADDHLTarget::BC)),
             0x0A => Some(Instruction::LD(LoadType::AFromIndirect(AFromIndirect::BC))),
             0x0B => Some(Instruction::DEC(IncTarget::BC)),
             0x0C => Some(Instruction::INC(IncTarget::C)),
             0x0D => Some(Instruction::DEC(IncTarget::C)),
             0x0E => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::C, LoadByteSource::D8))),
+            0x0F => Some(Instruction::RRCA()),
+            0x10 => Some(Instruction::STOP()),
+            0x11 => Some(Instruction::LD(LoadType::Word(WordByteTarget::DE, WordByteSource::U16))),
+            0x12 => Some(Instruction::LD(LoadType::IndirectFromA(IndirectFromA::DE))),
             0x13 => Some(Instruction::INC(IncTarget::DE)),
             0x14 => Some(Instruction::INC(IncTarget::D)),
             0x15 => Some(Instruction::DEC(IncTarget::D)),
             0x16 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::D, LoadByteSource::D8))),
             0x17 => Some(Instruction::RLA()),
-            0x18 => None, // TODO JR
+            0x18 => Some(Instruction::JR(JumpTest::Always)),
             0x19 => Some(Instruction::AddHL(ADDHLTarget::DE)),
             0x1A => Some(Instruction::LD(LoadType::AFromIndirect(AFromIndirect::DE))),
             0x1B => Some(Instruction::DEC(IncTarget::DE)),
@@ -35,15 +40,15 @@ impl Instruction {
             0x1D => Some(Instruction::DEC(IncTarget::E)),
             0x1E => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::E, LoadByteSource::D8))),
             0x1F => Some(Instruction::RRA()),
-            0x20 => None, //Todo JR
+            0x20 => Some(Instruction::JR(JumpTest::NotZero)),
             0x21 => Some(Instruction::LD(LoadType::Word(WordByteTarget::HL, WordByteSource::U16))),
             0x22 => Some(Instruction::LD(LoadType::IndirectFromA(IndirectFromA::HLPlus))),
             0x23 => Some(Instruction::INC(IncTarget::HL)),
             0x24 => Some(Instruction::INC(IncTarget::H)),
             0x25 => Some(Instruction::DEC(IncTarget::H)),
             0x26 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::H, LoadByteSource::D8))),
-            0x27 => None, // TODO DAA
-            0x28 => None, // TODO JR
+            0x27 => Some(Instruction::DAA()),
+            0x28 => Some(Instruction::JR(JumpTest::Zero)),
             0x29 => Some(Instruction::AddHL(ADDHLTarget::HL)),
             0x2A => Some(Instruction::LD(LoadType::AFromIndirect(AFromIndirect::HLPlus))),
             0x2B => Some(Instruction::DEC(IncTarget::HL)),
@@ -51,15 +56,15 @@ impl Instruction {
             0x2D => Some(Instruction::DEC(IncTarget::L)),
             0x2E => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::L, LoadByteSource::D8))),
             0x2F => Some(Instruction::CPL()),
-            0x30 => None, // TODO JR
+            0x30 => Some(Instruction::JR(JumpTest::NotCarry)),
             0x31 => Some(Instruction::LD(LoadType::Word(WordByteTarget::SP, WordByteSource::U16))),
             0x32 => Some(Instruction::LD(LoadType::IndirectFromA(IndirectFromA::HLMinus))),
             0x33 => Some(Instruction::INC(IncTarget::SP)),
-            0x34 => Some(Instruction::INC(IncTarget::HL)),
-            0x35 => Some(Instruction::DEC(IncTarget::HL)),
+            0x34 => Some(Instruction::INC(IncTarget::HLI)),
+            0x35 => Some(Instruction::DEC(IncTarget::HLI)),
             0x36 => Some(Instruction::LD(LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::D8))),
             0x37 => Some(Instruction::SCF()),
-            0x38 => None, // TODO JR
+            0x38 => Some(Instruction::JR(JumpTest::Carry)),
             0x39 => Some(Instruction::AddHL(ADDHLTarget::SP)),
             0x3A => Some(Instruction::LD(LoadType::AFromIndirect(AFromIndirect::HLMinus))),
             0x3B => Some(Instruction::DEC(IncTarget::SP)),
@@ -178,7 +183,7 @@ impl Instruction {
             0xAC => Some(Instruction::XOR(ArithmeticTarget::H)),
             0xAD => Some(Instruction::XOR(ArithmeticTarget::L)),
             0xAE => Some(Instruction::XOR(ArithmeticTarget::HL)),
-            0xAF => Some(Instruction::OR(ArithmeticTarget::A)),
+            0xAF => Some(Instruction::XOR(ArithmeticTarget::A)),
             0xB0 => Some(Instruction::OR(ArithmeticTarget::B)),
             0xB1 => Some(Instruction::OR(ArithmeticTarget::C)),
             0xB2 => Some(Instruction::OR(ArithmeticTarget::D)),
@@ -195,71 +200,70 @@ impl Instruction {
             0xBD => Some(Instruction::CP(ArithmeticTarget::L)),
             0xBE => Some(Instruction::CP(ArithmeticTarget::HL)),
             0xBF => Some(Instruction::CP(ArithmeticTarget::A)),
-            0xC0 => None, // TODO RT
+            0xC0 => Some(Instruction::RET(JumpTest::NotZero)),
             0xC1 => Some(Instruction::POP(StackTarget::BC)),
-            0xC2 => None, // TODO JP
-            0xC3 => None, // TODO JP
-            0xC4 => None, // TODO CALL
+            0xC2 => Some(Instruction::JP(JumpTest::NotZero)),
+            0xC3 => Some(Instruction::JP(JumpTest::Always)),
+            0xC4 => Some(Instruction::CALL(JumpTest::NotZero)),
             0xC5 => Some(Instruction::PUSH(StackTarget::BC)),
             0xC6 => Some(Instruction::ADD(ArithmeticTarget::PC)),
-            0xC7 => None, // TODO RST
-            0xC8 => None, // TODO REt
-            0xC9 => None, // TODO RET
-            0xCA => None, // TODO JP
+            0xC7 => Some(Instruction::RST(0x00)),
+            0xC8 => Some(Instruction::RET(JumpTest::Zero)),
+            0xC9 => Some(Instruction::RET(JumpTest::Always)),
+            0xCA => Some(Instruction::JP(JumpTest::Zero)),
             0xCB => None,
-            0xCC => None, // TODO CAll
-            0xCD => None, // TODO call u16
+            0xCC => Some(Instruction::CALL(JumpTest::Zero)),
+            0xCD => Some(Instruction::CALL(JumpTest::Always)),
             0xCE => Some(Instruction::ADC(ArithmeticTarget::PC)),
-            0xCF => None, // TODO RST
-            0xD0 => None, // TODO ret
+            0xCF => Some(Instruction::RST(0x08)),
+            0xD0 => Some(Instruction::RET(JumpTest::NotCarry)),
             0xD1 => Some(Instruction::POP(StackTarget::DE)),
-            0xD2 => None, // TODO JP
-            0xD3 => None,
-            0xD4 => None, // TODO CALL
+            0xD2 => Some(Instruction::JP(JumpTest::NotCarry)),
+            0xD3 => Some(Instruction::ILLEGAL(0xD3)),
+            0xD4 => Some(Instruction::CALL(JumpTest::NotCarry)),
             0xD5 => Some(Instruction::PUSH(StackTarget::DE)),
             0xD6 => Some(Instruction::SUB(ArithmeticTarget::PC)),
-            0xD7 => None, // TODO RST
-            0xD8 => None, // TODO Ret
-            0xD9 => None, // TODORETI
-            0xDA => None, // TODO JP
-            0xDB => None,
-            0xDC => None, // TODO CAll
-            0xDD => None,
+            0xD7 => Some(Instruction::RST(0x10)),
+            0xD8 => Some(Instruction::RET(JumpTest::Carry)),
+            0xD9 => Some(Instruction::RETI()),
+            0xDA => Some(Instruction::JP(JumpTest::Carry)),
+            0xDB => Some(Instruction::ILLEGAL(0xDB)),
+            0xDC => Some(Instruction::CALL(JumpTest::Carry)),
+            0xDD => Some(Instruction::ILLEGAL(0xDD)),
             0xDE => Some(Instruction::SBC(ArithmeticTarget::PC)),
-            0xDF => None, // TODO RST
+            0xDF => Some(Instruction::RST(0x18)),
             0xE0 => Some(Instruction::LD(LoadType::ByteAddressFromA(ByteAddressFromA::FF00U8))),
             0xE1 => Some(Instruction::POP(StackTarget::Hl)),
             0xE2 => Some(Instruction::LD(LoadType::ByteAddressFromA(ByteAddressFromA::FFOOC))),
-            0xE3 => None,
-            0xE4 => None,
+            0xE3 => Some(Instruction::ILLEGAL(0xE3)),
+            0xE4 => Some(Instruction::ILLEGAL(0xE4)),
             0xE5 => Some(Instruction::PUSH(StackTarget::Hl)),
             0xE6 => Some(Instruction::AND(ArithmeticTarget::PC)),
-            0xE7 => None, // TODO RST
+            0xE7 => Some(Instruction::RST(0x20)),
             0xE8 => Some(Instruction::ADDSP()),
-            0xE9 => None, // TODO JP HL
+            0xE9 => Some(Instruction::JPHL()),
             0xEA => Some(Instruction::LD(LoadType::ByteAddressFromA(ByteAddressFromA::U16))),
-            0xEB => None,
-            0xEC => None,
-            0xED => None,
+            0xEB => Some(Instruction::ILLEGAL(0xEB)),
+            0xEC => Some(Instruction::ILLEGAL(0xEC)),
+            0xED => Some(Instruction::ILLEGAL(0xED)),
             0xEE => Some(Instruction::XOR(ArithmeticTarget::PC)),
-            0xEF => None, // TODO RST
+            0xEF => Some(Instruction::RST(0x28)),
             0xF0 => Some(Instruction::LD(LoadType::AFromByteAddress(AFromByteAddress::FF00U8))),
             0xF1 => Some(Instruction::POP(StackTarget::AF)),
             0xF2 => Some(Instruction::LD(LoadType::AFromByteAddress(AFromByteAddress::FFOOC))),
-            0xF3 => None, // TODO DI
-            0xF4 => None,
+            0xF3 => Some(Instruction::DI()),
+            0xF4 => Some(Instruction::ILLEGAL(0xF4)),
             0xF5 => Some(Instruction::PUSH(StackTarget::AF)),
             0xF6 => Some(Instruction::OR(ArithmeticTarget::PC)),
-            0xF7 => None, //TODO RST
+            0xF7 => Some(Instruction::RST(0x30)),
             0xF8 => Some(Instruction::LDHL()),
             0xF9 => Some(Instruction::LDSP()),
             0xFA => Some(Instruction::LD(LoadType::AFromByteAddress(AFromByteAddress::U16))),
-            0xFB => None, // TODO EI
-            0xFC => None,
-            0xFD => None,
+            0xFB => Some(Instruction::EI()),
+            0xFC => Some(Instruction::ILLEGAL(0xFC)),
+            0xFD => Some(Instruction::ILLEGAL(0xFD)),
             0xFE => Some(Instruction::CP(ArithmeticTarget::PC)),
-            0xFF => None, // TODO RST
-            _ => /* TODO: Add mapping for rest of instructions */ None
+            0xFF => Some(Instruction::RST(0x38)),
         }
     }
     pub fn from_byte_prefixed(byte: u8) -> Option<Instruction> {
@@ -365,8 +369,101 @@ impl Instruction {
                 let reg = byte & 0b111;
                 Some(Instruction::SET(Self::decode_prefix_target(reg), bit))
             }
+        }
+    }
+
+    /// Number of T-cycles the instruction takes, `branch_taken` only matters for conditional JP/JR/CALL/RET
+    pub fn cycles(&self, branch_taken: bool) -> u8 {
+        match self {
+            Instruction::NOP() | Instruction::HALT() | Instruction::STOP() | Instruction::DI() | Instruction::EI() => 4,
+            Instruction::ILLEGAL(_) => 4,
+            Instruction::CCF() | Instruction::SCF() | Instruction::CPL() | Instruction::DAA() => 4,
+            Instruction::RRA() | Instruction::RLA() | Instruction::RRCA() | Instruction::RRLA() => 4,
+            Instruction::ADD(target) | Instruction::ADC(target) | Instruction::SUB(target) | Instruction::SBC(target)
+            | Instruction::AND(target) | Instruction::OR(target) | Instruction::XOR(target) | Instruction::CP(target) => {
+                match target {
+                    ArithmeticTarget::HL | ArithmeticTarget::PC => 8,
+                    _ => 4,
+                }
+            }
+            Instruction::INC(target) | Instruction::DEC(target) => match target {
+                IncTarget::HLI => 12,
+                IncTarget::BC | IncTarget::DE | IncTarget::HL | IncTarget::SP => 8,
+                _ => 4,
+            },
+            Instruction::AddHL(_) => 8,
+            Instruction::ADDSP() => 16,
+            Instruction::LDHL() => 12,
+            Instruction::LDSP() => 8,
+            // prefixed instructions include the 4 cycles spent fetching the 0xCB byte
+            Instruction::BIT(target, _) => match target {
+                PrefixTarget::HL => 12,
+                _ => 8,
+            },
+            Instruction::RESET(target, _) | Instruction::SET(target, _) => match target {
+                PrefixTarget::HL => 16,
+                _ => 8,
+            },
+            Instruction::SRL(target) | Instruction::RR(target) | Instruction::RL(target) | Instruction::RRC(target)
+            | Instruction::RLC(target) | Instruction::SRA(target) | Instruction::SLA(target) | Instruction::SWAP(target) => {
+                match target {
+                    PrefixTarget::HL => 16,
+                    _ => 8,
+                }
+            }
+            Instruction::JP(_) => if branch_taken { 16 } else { 12 },
+            Instruction::JPHL() => 4,
+            Instruction::JR(_) => if branch_taken { 12 } else { 8 },
+            Instruction::CALL(_) => if branch_taken { 24 } else { 12 },
+            Instruction::RET(JumpTest::Always) => 16,
+            Instruction::RET(_) => if branch_taken { 20 } else { 8 },
+            Instruction::RETI() => 16,
+            Instruction::RST(_) => 16,
+            Instruction::PUSH(_) => 16,
+            Instruction::POP(_) => 12,
+            Instruction::LD(load_type) => match load_type {
+                LoadType::Byte(LoadByteTarget::HLI, LoadByteSource::D8) => 12,
+                LoadType::Byte(LoadByteTarget::HLI, _) => 8,
+                LoadType::Byte(_, LoadByteSource::HLI) | LoadType::Byte(_, LoadByteSource::D8) => 8,
+                LoadType::Byte(_, _) => 4,
+                LoadType::Word(WordByteTarget::U16, _) => 20,
+                LoadType::Word(_, _) => 12,
+                LoadType::AFromIndirect(_) | LoadType::IndirectFromA(_) => 8,
+                LoadType::AFromByteAddress(address) => match address {
+                    AFromByteAddress::U16 => 16,
+                    AFromByteAddress::FF00U8 => 12,
+                    AFromByteAddress::FFOOC => 8,
+                },
+                LoadType::ByteAddressFromA(address) => match address {
+                    ByteAddressFromA::U16 => 16,
+                    ByteAddressFromA::FF00U8 => 12,
+                    ByteAddressFromA::FFOOC => 8,
+                },
+            },
+        }
+    }
 
-            _ => None,
+    /// Size of the instruction in bytes, including the 0xCB prefix and any immediate operands
+    pub fn length(&self) -> u16 {
+        match self {
+            Instruction::BIT(_, _) | Instruction::RESET(_, _) | Instruction::SET(_, _) | Instruction::SRL(_)
+            | Instruction::RR(_) | Instruction::RL(_) | Instruction::RRC(_) | Instruction::RLC(_)
+            | Instruction::SRA(_) | Instruction::SLA(_) | Instruction::SWAP(_) => 2,
+            Instruction::ADD(ArithmeticTarget::PC) | Instruction::ADC(ArithmeticTarget::PC)
+            | Instruction::SUB(ArithmeticTarget::PC) | Instruction::SBC(ArithmeticTarget::PC)
+            | Instruction::AND(ArithmeticTarget::PC) | Instruction::OR(ArithmeticTarget::PC)
+            | Instruction::XOR(ArithmeticTarget::PC) | Instruction::CP(ArithmeticTarget::PC) => 2,
+            Instruction::JR(_) | Instruction::ADDSP() | Instruction::LDHL() | Instruction::STOP() => 2,
+            Instruction::JP(_) | Instruction::CALL(_) => 3,
+            Instruction::LD(load_type) => match load_type {
+                LoadType::Byte(_, LoadByteSource::D8) => 2,
+                LoadType::Byte(_, _) => 1,
+                LoadType::Word(_, _) => 3,
+                LoadType::AFromByteAddress(AFromByteAddress::U16) | LoadType::ByteAddressFromA(ByteAddressFromA::U16) => 3,
+                LoadType::AFromByteAddress(AFromByteAddress::FF00U8) | LoadType::ByteAddressFromA(ByteAddressFromA::FF00U8) => 2,
+                _ => 1,
+            },
+            _ => 1,
         }
     }
 
@@ -405,6 +502,7 @@ pub enum Instruction {
     RRCA(),
     RRLA(),
     CPL(),
+    DAA(),
     ADDSP(),
     LDHL(),
     //fix target
@@ -420,11 +518,23 @@ pub enum Instruction {
     SLA(PrefixTarget),
     SWAP(PrefixTarget),
     JP(JumpTest),
+    JPHL(),
+    JR(JumpTest),
+    CALL(JumpTest),
+    RET(JumpTest),
+    // the u8 is the restart vector the instruction jumps to
+    RST(u8),
+    RETI(),
+    DI(),
+    EI(),
     LD(LoadType),
     POP(StackTarget),
     PUSH(StackTarget),
     NOP(),
     HALT(),
+    STOP(),
+    // one of the unused opcodes that lock up the CPU, the u8 is the opcode itself
+    ILLEGAL(u8),
 }
 pub enum StackTarget {
     AF,
@@ -432,7 +542,6 @@ pub enum StackTarget {
     DE,
     Hl
 }
-//finish TODO
 pub enum ArithmeticTarget {
     A,
     B,
@@ -464,6 +573,7 @@ pub enum IncTarget {
     H,
     L,
     HL,
+    HLI,
     BC,
     DE,
     SP
@@ -0,0 +1,132 @@
+pub const VBLANK_VECTOR: u16 = 0x40;
+pub const LCD_STAT_VECTOR: u16 = 0x48;
+pub const TIMER_VECTOR: u16 = 0x50;
+pub const SERIAL_VECTOR: u16 = 0x58;
+pub const JOYPAD_VECTOR: u16 = 0x60;
+
+// Servicing an interrupt takes 5 machine cycles (2 wait states, 2 for the push, 1 for the jump)
+pub const INTERRUPT_DISPATCH_CYCLES: u8 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptSource {
+    VBlank,
+    LCDStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+impl InterruptSource {
+    /// Ordered from highest to lowest priority
+    pub const ALL: [InterruptSource; 5] = [
+        InterruptSource::VBlank,
+        InterruptSource::LCDStat,
+        InterruptSource::Timer,
+        InterruptSource::Serial,
+        InterruptSource::Joypad,
+    ];
+
+    /// The bit this source occupies in both IF and IE
+    pub fn bit(&self) -> u8 {
+        match self {
+            InterruptSource::VBlank => 0b0000_0001,
+            InterruptSource::LCDStat => 0b0000_0010,
+            InterruptSource::Timer => 0b0000_0100,
+            InterruptSource::Serial => 0b0000_1000,
+            InterruptSource::Joypad => 0b0001_0000,
+        }
+    }
+
+    pub fn vector(&self) -> u16 {
+        match self {
+            InterruptSource::VBlank => VBLANK_VECTOR,
+            InterruptSource::LCDStat => LCD_STAT_VECTOR,
+            InterruptSource::Timer => TIMER_VECTOR,
+            InterruptSource::Serial => SERIAL_VECTOR,
+            InterruptSource::Joypad => JOYPAD_VECTOR,
+        }
+    }
+}
+
+pub struct InterruptController {
+    pub flag: u8,   // Interrupt Flag IF ($FF0F)
+    pub enable: u8, // Interrupt Enable IE ($FFFF)
+    pub ime: bool,  // Interrupt Master Enable, only reachable through EI/DI/RETI
+    ei_delay: u8,   // EI only takes effect after the instruction following it
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        InterruptController {
+            flag: 0,
+            enable: 0,
+            ime: false,
+            ei_delay: 0,
+        }
+    }
+
+    /// The upper 3 bits of IF are unused and always read back as 1
+    pub fn read_flag(&self) -> u8 {
+        self.flag | 0b1110_0000
+    }
+
+    pub fn write_flag(&mut self, value: u8) {
+        self.flag = value & 0b0001_1111;
+    }
+
+    pub fn request(&mut self, source: InterruptSource) {
+        self.flag |= source.bit();
+    }
+
+    /// Bits that are both requested and enabled, regardless of IME
+    pub fn pending(&self) -> u8 {
+        self.flag & self.enable & 0b0001_1111
+    }
+
+    pub fn highest_priority(&self) -> Option<InterruptSource> {
+        let pending = self.pending();
+        InterruptSource::ALL.into_iter().find(|source| pending & source.bit() != 0)
+    }
+
+    /// Clears the request bit and IME at the start of a dispatch
+    pub fn acknowledge(&mut self, source: InterruptSource) {
+        self.flag &= !source.bit();
+        self.ime = false;
+        self.ei_delay = 0;
+    }
+
+    /// EI: IME is set once the next instruction has finished
+    pub fn schedule_enable(&mut self) {
+        if !self.ime && self.ei_delay == 0 {
+            self.ei_delay = 2;
+        }
+    }
+
+    /// RETI: IME is set straight away
+    pub fn enable(&mut self) {
+        self.ime = true;
+        self.ei_delay = 0;
+    }
+
+    /// DI: also cancels an EI that hasn't taken effect yet
+    pub fn disable(&mut self) {
+        self.ime = false;
+        self.ei_delay = 0;
+    }
+
+    /// Called once after every instruction to count down the EI delay
+    pub fn step(&mut self) {
+        if self.ei_delay > 0 {
+            self.ei_delay -= 1;
+            if self.ei_delay == 0 {
+                self.ime = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+const SELECT_DIRECTIONS: u8 = 0b0001_0000;
+const SELECT_BUTTONS: u8 = 0b0010_0000;
+
+pub struct Keypad {
+    select: u8,     // bits 4-5 of P1 ($FF00), a 0 selects that row
+    directions: u8, // Right, Left, Up, Down in bits 0-3, a 0 means pressed
+    buttons: u8,    // A, B, Select, Start in bits 0-3, a 0 means pressed
+}
+
+impl Default for Keypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Keypad {
+    pub fn new() -> Self {
+        Keypad {
+            select: SELECT_DIRECTIONS | SELECT_BUTTONS,
+            directions: 0x0F,
+            buttons: 0x0F,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        0b1100_0000 | self.select | self.lines()
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.select = value & (SELECT_DIRECTIONS | SELECT_BUTTONS);
+    }
+
+    /// Returns true when a selected input line went from high to low, which raises the joypad interrupt
+    pub fn press(&mut self, key: Key) -> bool {
+        let before = self.lines();
+        let (row, bit) = Self::locate(key);
+        match row {
+            SELECT_DIRECTIONS => self.directions &= !bit,
+            _ => self.buttons &= !bit,
+        }
+        before & !self.lines() != 0
+    }
+
+    pub fn release(&mut self, key: Key) {
+        let (row, bit) = Self::locate(key);
+        match row {
+            SELECT_DIRECTIONS => self.directions |= bit,
+            _ => self.buttons |= bit,
+        }
+    }
+
+    fn lines(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            lines &= self.directions;
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            lines &= self.buttons;
+        }
+        lines
+    }
+
+    fn locate(key: Key) -> (u8, u8) {
+        match key {
+            Key::Right => (SELECT_DIRECTIONS, 0b0001),
+            Key::Left => (SELECT_DIRECTIONS, 0b0010),
+            Key::Up => (SELECT_DIRECTIONS, 0b0100),
+            Key::Down => (SELECT_DIRECTIONS, 0b1000),
+            Key::A => (SELECT_BUTTONS, 0b0001),
+            Key::B => (SELECT_BUTTONS, 0b0010),
+            Key::Select => (SELECT_BUTTONS, 0b0100),
+            Key::Start => (SELECT_BUTTONS, 0b1000),
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! A Game Boy emulator core. The `gb_emu` binary is a thin front end over it, tools (debuggers,
+//! tracers, test harnesses) drive the `CPU` directly and watch it through `Hooks`, its `CallStack`
+//! and `CpuState` snapshots, or extend the memory map with a `BusDevice`.
+pub mod cpu;
+pub mod hooks;
+pub mod call_stack;
+pub mod bus;
+pub mod device;
+pub mod instruction;
+pub mod interrupts;
+pub mod keypad;
+pub mod model;
+mod opcodes;
+pub mod cartride;
+pub mod disassembler;
+#[cfg(test)]
+mod assembler;
+pub mod dma;
+mod scheduler;
+pub mod serial;
+pub mod timer;
+pub mod test_roms;
+pub mod GPU;
+
+pub use bus::{Bus, MemoryBus};
+pub use call_stack::{CallFrame, CallStack};
+pub use cartride::Cartridge;
+pub use cpu::{CPU, CpuState};
+pub use device::BusDevice;
+pub use hooks::Hooks;
+pub use model::Model;
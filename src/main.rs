@@ -1,9 +1,17 @@
-mod cpu;
-mod bus;
-mod instruction;
-mod cartride;
-mod timer;
-pub mod GPU;
-fn main() {
+use std::path::Path;
+use gb_emu::{test_roms, Cartridge};
 
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    // gb_emu --blargg/--mooneye <rom> runs a test ROM without a window and exits with its result
+    if let [flag, rom] = args.as_slice() {
+        if let Ok(cartridge) = Cartridge::load(rom) {
+            println!("Loaded ROM: {}, MBC: {:?}", cartridge.title, cartridge.mbc_type);
+        }
+        match flag.as_str() {
+            "--blargg" => std::process::exit(test_roms::blargg::run_headless(Path::new(rom))),
+            "--mooneye" => std::process::exit(test_roms::mooneye::run_headless(Path::new(rom))),
+            _ => {}
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,22 @@
+/// The Game Boy hardware revision being emulated, each one leaves the registers in a slightly
+/// different state after its boot ROM hands over control at 0x0100
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    DMG0, // early original Game Boy
+    DMG,  // original Game Boy
+    MGB,  // Game Boy Pocket / Light
+    SGB,  // Super Game Boy
+    SGB2, // Super Game Boy 2
+    CGB,  // Game Boy Color
+    AGB,  // Game Boy Advance running Game Boy software
+}
+
+impl Model {
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::CGB | Model::AGB)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::SGB | Model::SGB2)
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::LazyLock;
+use crate::cpu::CPU;
+use crate::instruction::{Instruction, JumpTest, LoadType};
+
+/// Executes an already decoded instruction and returns the address of the next one
+pub type Handler<B> = fn(&mut CPU<B>, &Instruction) -> u16;
+
+/// Which of the CPU's handlers executes an opcode, one per kind of instruction so a handler only
+/// has to look at its own operands. The table stores this rather than a `Handler` so it can be
+/// shared by CPUs on any kind of bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Nop,
+    Halt,
+    Stop,
+    Illegal,
+    Di,
+    Ei,
+    LoadByte,
+    LoadWord,
+    LoadAFromIndirect,
+    LoadIndirectFromA,
+    LoadAFromAddress,
+    LoadAddressFromA,
+    Pop,
+    Push,
+    LoadHlSpOffset,
+    LoadSpHl,
+    Jp,
+    JpHl,
+    Jr,
+    Call,
+    Ret,
+    Rst,
+    Reti,
+    Bit,
+    Res,
+    Set,
+    Srl,
+    Rr,
+    Rl,
+    Rrc,
+    Rlc,
+    Sra,
+    Sla,
+    Swap,
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Or,
+    Xor,
+    Cp,
+    Inc,
+    Dec,
+    AddHl,
+    AddSp,
+    Rra,
+    Rla,
+    Rrca,
+    Rlca,
+    Ccf,
+    Scf,
+    Cpl,
+    Daa,
+}
+
+impl Operation {
+    pub const COUNT: usize = Operation::Daa as usize + 1;
+
+    pub fn of(instruction: &Instruction) -> Operation {
+        match instruction {
+            Instruction::NOP() => Operation::Nop,
+            Instruction::HALT() => Operation::Halt,
+            Instruction::STOP() => Operation::Stop,
+            Instruction::ILLEGAL(_) => Operation::Illegal,
+            Instruction::DI() => Operation::Di,
+            Instruction::EI() => Operation::Ei,
+            Instruction::LD(LoadType::Byte(_, _)) => Operation::LoadByte,
+            Instruction::LD(LoadType::Word(_, _)) => Operation::LoadWord,
+            Instruction::LD(LoadType::AFromIndirect(_)) => Operation::LoadAFromIndirect,
+            Instruction::LD(LoadType::IndirectFromA(_)) => Operation::LoadIndirectFromA,
+            Instruction::LD(LoadType::AFromByteAddress(_)) => Operation::LoadAFromAddress,
+            Instruction::LD(LoadType::ByteAddressFromA(_)) => Operation::LoadAddressFromA,
+            Instruction::POP(_) => Operation::Pop,
+            Instruction::PUSH(_) => Operation::Push,
+            Instruction::LDHL() => Operation::LoadHlSpOffset,
+            Instruction::LDSP() => Operation::LoadSpHl,
+            Instruction::JP(_) => Operation::Jp,
+            Instruction::JPHL() => Operation::JpHl,
+            Instruction::JR(_) => Operation::Jr,
+            Instruction::CALL(_) => Operation::Call,
+            Instruction::RET(_) => Operation::Ret,
+            Instruction::RST(_) => Operation::Rst,
+            Instruction::RETI() => Operation::Reti,
+            Instruction::BIT(_, _) => Operation::Bit,
+            Instruction::RESET(_, _) => Operation::Res,
+            Instruction::SET(_, _) => Operation::Set,
+            Instruction::SRL(_) => Operation::Srl,
+            Instruction::RR(_) => Operation::Rr,
+            Instruction::RL(_) => Operation::Rl,
+            Instruction::RRC(_) => Operation::Rrc,
+            Instruction::RLC(_) => Operation::Rlc,
+            Instruction::SRA(_) => Operation::Sra,
+            Instruction::SLA(_) => Operation::Sla,
+            Instruction::SWAP(_) => Operation::Swap,
+            Instruction::ADD(_) => Operation::Add,
+            Instruction::ADC(_) => Operation::Adc,
+            Instruction::SUB(_) => Operation::Sub,
+            Instruction::SBC(_) => Operation::Sbc,
+            Instruction::AND(_) => Operation::And,
+            Instruction::OR(_) => Operation::Or,
+            Instruction::XOR(_) => Operation::Xor,
+            Instruction::CP(_) => Operation::Cp,
+            Instruction::INC(_) => Operation::Inc,
+            Instruction::DEC(_) => Operation::Dec,
+            Instruction::AddHL(_) => Operation::AddHl,
+            Instruction::ADDSP() => Operation::AddSp,
+            Instruction::RRA() => Operation::Rra,
+            Instruction::RLA() => Operation::Rla,
+            Instruction::RRCA() => Operation::Rrca,
+            Instruction::RRLA() => Operation::Rlca,
+            Instruction::CCF() => Operation::Ccf,
+            Instruction::SCF() => Operation::Scf,
+            Instruction::CPL() => Operation::Cpl,
+            Instruction::DAA() => Operation::Daa,
+        }
+    }
+}
+
+/// Everything known about an opcode ahead of time, so `CPU::step` never has to decode
+pub struct Opcode {
+    pub instruction: Instruction,
+    pub cycles: u8,       // T-cycles when a conditional branch isn't taken (or for any other instruction)
+    pub cycles_taken: u8, // T-cycles when a conditional branch is taken
+    pub operation: Operation,
+}
+
+impl Opcode {
+    fn new(instruction: Instruction) -> Self {
+        // unconditional jumps never take the "not taken" path
+        let always_taken = matches!(
+            &instruction,
+            Instruction::JP(JumpTest::Always) | Instruction::JR(JumpTest::Always)
+            | Instruction::CALL(JumpTest::Always) | Instruction::RET(JumpTest::Always)
+        );
+        Opcode {
+            cycles: instruction.cycles(always_taken),
+            cycles_taken: instruction.cycles(true),
+            operation: Operation::of(&instruction),
+            instruction,
+        }
+    }
+}
+
+/// Unprefixed opcodes at 0x000-0x0FF, 0xCB-prefixed ones at 0x100-0x1FF
+static OPCODES: LazyLock<Vec<Option<Opcode>>> = LazyLock::new(|| {
+    let unprefixed = (0..=0xFF).map(|byte| Instruction::from_byte(byte, false));
+    let prefixed = (0..=0xFF).map(|byte| Instruction::from_byte(byte, true));
+    unprefixed.chain(prefixed).map(|instruction| instruction.map(Opcode::new)).collect()
+});
+
+/// None only for 0xCB itself, which is the prefix rather than an opcode, the unused opcodes decode to
+/// `Instruction::ILLEGAL`
+pub fn lookup(byte: u8, prefixed: bool) -> Option<&'static Opcode> {
+    let index = if prefixed { 0x100 | byte as usize } else { byte as usize };
+    OPCODES[index].as_ref()
+}
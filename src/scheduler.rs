@@ -0,0 +1,125 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Things that happen at a known point in time, so the timer, serial port and PPU only do work
+/// when one of them is due instead of counting every cycle. The bus still checks for a due event
+/// on every M-cycle, this keeps the components simple rather than making the emulator faster.
+/// Events due at the same time run in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Event {
+    TimerOverflow,
+    SerialTransfer,
+    PpuModeChange,
+}
+
+const EVENT_COUNT: usize = 3;
+
+/// A min-heap of events keyed by the T-cycle they're due at. Each event is pending at most once,
+/// scheduling it again moves it and leaves the old heap entry behind to be skipped
+pub struct Scheduler {
+    queue: BinaryHeap<Reverse<(u64, Event)>>,
+    deadlines: [Option<u64>; EVENT_COUNT], // when each event is really due
+    next: u64, // the earliest of `deadlines`, so the bus can check every cycle without touching the heap
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            queue: BinaryHeap::new(),
+            deadlines: [None; EVENT_COUNT],
+            next: u64::MAX,
+        }
+    }
+
+    pub fn schedule(&mut self, event: Event, at: u64) {
+        self.deadlines[event as usize] = Some(at);
+        self.queue.push(Reverse((at, event)));
+        self.update_next();
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.deadlines[event as usize] = None;
+        self.update_next();
+    }
+
+    /// Removes and returns the earliest event due at or before `now`, along with when it was due
+    pub fn pop_due(&mut self, now: u64) -> Option<(u64, Event)> {
+        if now < self.next {
+            return None;
+        }
+        while let Some(&Reverse((at, event))) = self.queue.peek() {
+            if at > now {
+                return None;
+            }
+            self.queue.pop();
+            if self.deadlines[event as usize] == Some(at) {
+                self.deadlines[event as usize] = None;
+                self.update_next();
+                return Some((at, event));
+            }
+        }
+        None
+    }
+
+    fn update_next(&mut self) {
+        self.next = self.deadlines.iter().flatten().copied().min().unwrap_or(u64::MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_come_out_in_deadline_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::PpuModeChange, 300);
+        scheduler.schedule(Event::TimerOverflow, 100);
+        scheduler.schedule(Event::SerialTransfer, 200);
+        assert_eq!(scheduler.pop_due(99), None);
+        assert_eq!(scheduler.pop_due(250), Some((100, Event::TimerOverflow)));
+        assert_eq!(scheduler.pop_due(250), Some((200, Event::SerialTransfer)));
+        assert_eq!(scheduler.pop_due(250), None);
+        assert_eq!(scheduler.pop_due(300), Some((300, Event::PpuModeChange)));
+        assert_eq!(scheduler.pop_due(u64::MAX), None);
+    }
+
+    #[test]
+    fn events_due_together_come_out_in_event_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::PpuModeChange, 50);
+        scheduler.schedule(Event::TimerOverflow, 50);
+        assert_eq!(scheduler.pop_due(50), Some((50, Event::TimerOverflow)));
+        assert_eq!(scheduler.pop_due(50), Some((50, Event::PpuModeChange)));
+    }
+
+    #[test]
+    fn rescheduling_skips_the_stale_entry() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::TimerOverflow, 100);
+        scheduler.schedule(Event::TimerOverflow, 400);
+        assert_eq!(scheduler.pop_due(399), None);
+        assert_eq!(scheduler.pop_due(400), Some((400, Event::TimerOverflow)));
+
+        // moving it earlier works too, and the later entry doesn't fire a second time
+        scheduler.schedule(Event::TimerOverflow, 900);
+        scheduler.schedule(Event::TimerOverflow, 500);
+        assert_eq!(scheduler.pop_due(1000), Some((500, Event::TimerOverflow)));
+        assert_eq!(scheduler.pop_due(1000), None);
+    }
+
+    #[test]
+    fn cancelled_events_never_fire() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::SerialTransfer, 100);
+        scheduler.schedule(Event::PpuModeChange, 200);
+        scheduler.cancel(Event::SerialTransfer);
+        assert_eq!(scheduler.pop_due(150), None);
+        assert_eq!(scheduler.pop_due(200), Some((200, Event::PpuModeChange)));
+
+        // scheduling it again after a cancel only fires the new deadline
+        scheduler.schedule(Event::SerialTransfer, 300);
+        assert_eq!(scheduler.pop_due(300), Some((300, Event::SerialTransfer)));
+        assert_eq!(scheduler.pop_due(300), None);
+    }
+}
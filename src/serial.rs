@@ -0,0 +1,56 @@
+// One bit is shifted out every 512 T-cycles on the internal 8192 Hz clock
+pub const CYCLES_PER_BYTE: u64 = 8 * 512;
+
+const TRANSFER_START: u8 = 0b1000_0000;
+const INTERNAL_CLOCK: u8 = 0b0000_0001;
+
+/// The link port, with nothing plugged in so every byte sent out comes back as 0xFF.
+/// Everything sent is also kept in `output`, test ROMs print their results this way
+pub struct Serial {
+    pub data: u8,    // SB ($FF01)
+    pub control: u8, // SC ($FF02)
+    output: Vec<u8>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            output: Vec::new(),
+        }
+    }
+
+    /// Bits 1-6 are unused and always read back as 1
+    pub fn read_control(&self) -> u8 {
+        self.control | 0b0111_1110
+    }
+
+    /// Returns true when this starts a transfer, which finishes `CYCLES_PER_BYTE` later
+    pub fn write_control(&mut self, value: u8) -> bool {
+        self.control = value;
+        // With the external clock nothing happens until a partner that never comes drives it
+        if value & (TRANSFER_START | INTERNAL_CLOCK) == TRANSFER_START | INTERNAL_CLOCK {
+            self.output.push(self.data);
+            return true;
+        }
+        false
+    }
+
+    /// Ends the transfer, the caller raises the serial interrupt
+    pub fn finish_transfer(&mut self) {
+        self.data = 0xFF;
+        self.control &= !TRANSFER_START;
+    }
+
+    /// Every byte sent so far
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+}
@@ -0,0 +1,131 @@
+//! Blargg's cpu_instrs, instr_timing and mem_timing ROMs print their results over the link
+//! port, which `Serial` keeps a copy of. A ROM is done once a line saying "Passed" or "Failed"
+//! has been printed in full.
+use std::path::Path;
+use super::CPU_CLOCK_HZ;
+use crate::model::Model;
+
+// cpu_instrs takes close to a minute of emulated time to get through all of its tests
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+impl Outcome {
+    /// What the headless runner exits with
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Passed => 0,
+            Outcome::Failed => 1,
+            Outcome::TimedOut => 2,
+        }
+    }
+}
+
+pub struct Report {
+    pub outcome: Outcome,
+    pub output: String, // everything the ROM sent over serial
+}
+
+/// Runs `rom` until it reports a result or `timeout_seconds` of emulated time have gone by
+pub fn run(rom: &Path, timeout_seconds: u64) -> Result<Report, String> {
+    let mut cpu = super::load(rom, Model::DMG)?;
+    let budget = timeout_seconds * CPU_CLOCK_HZ;
+    let mut elapsed = 0;
+    let mut seen = 0;
+    while elapsed < budget {
+        let cycles = match cpu.step() {
+            Ok(cycles) => cycles,
+            Err(error) => return Err(format!("{}\n{}", error, cpu.call_stack())),
+        };
+        elapsed += cycles as u64;
+
+        let output = cpu.bus().serial.output();
+        if output.len() == seen {
+            continue;
+        }
+        seen = output.len();
+        let output = String::from_utf8_lossy(output);
+        if let Some(outcome) = outcome(&output) {
+            return Ok(Report { outcome, output: output.into_owned() });
+        }
+    }
+    Ok(Report {
+        outcome: Outcome::TimedOut,
+        output: String::from_utf8_lossy(cpu.bus().serial.output()).into_owned(),
+    })
+}
+
+/// Runs `rom` from the command line, printing what it sent and returning the process exit code
+pub fn run_headless(rom: &Path) -> i32 {
+    match run(rom, DEFAULT_TIMEOUT_SECONDS) {
+        Ok(report) => {
+            println!("{}", report.output);
+            if report.outcome == Outcome::TimedOut {
+                eprintln!("Timed out after {} seconds", DEFAULT_TIMEOUT_SECONDS);
+            }
+            report.outcome.exit_code()
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            Outcome::Failed.exit_code()
+        }
+    }
+}
+
+fn outcome(output: &str) -> Option<Outcome> {
+    // wait for the end of the line, failures are followed by the test number
+    if !output.ends_with('\n') {
+        return None;
+    }
+    if output.contains("Failed") {
+        Some(Outcome::Failed)
+    } else if output.contains("Passed") {
+        Some(Outcome::Passed)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_roms::{find_roms, suite_dir};
+
+    #[test]
+    fn outcome_waits_for_a_whole_result_line() {
+        assert_eq!(outcome(""), None);
+        assert_eq!(outcome("cpu_instrs\n\n01:ok  02:ok  "), None);
+        assert_eq!(outcome("instr_timing\n\n\nPass"), None);
+        assert_eq!(outcome("instr_timing\n\n\nPassed"), None);
+        assert_eq!(outcome("instr_timing\n\n\nPassed\n"), Some(Outcome::Passed));
+        assert_eq!(outcome("cpu_instrs\n\n01:ok  02:01  \n\nFailed 1 tests.\n"), Some(Outcome::Failed));
+    }
+
+    #[test]
+    fn failures_are_reported_with_their_test_number() {
+        assert_eq!(outcome("02-interrupts\n\n\nEI\nFailed #2\n"), Some(Outcome::Failed));
+        assert_eq!(outcome("02-interrupts\n\n\nEI\nFailed #2"), None);
+    }
+
+    #[test]
+    fn blargg_roms() {
+        let Some(dir) = suite_dir("BLARGG_ROMS", "blargg") else {
+            eprintln!("Blargg test ROMs not found, set BLARGG_ROMS to run them");
+            return;
+        };
+        let mut failures = Vec::new();
+        for rom in find_roms(&dir) {
+            match run(&rom, DEFAULT_TIMEOUT_SECONDS) {
+                Ok(report) if report.outcome == Outcome::Passed => {}
+                Ok(report) => failures.push(format!("{}: {:?}\n{}", rom.display(), report.outcome, report.output)),
+                Err(error) => failures.push(format!("{}: {}", rom.display(), error)),
+            }
+        }
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,49 @@
+//! Headless runners for the test ROM suites that report their results without needing a screen.
+//!
+//! The ROMs aren't checked in, each suite is looked up in its own directory under `tests/`
+//! (or wherever its environment variable points) and the tests pass without doing anything
+//! when it isn't there.
+pub mod blargg;
+pub mod mooneye;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::bus::MemoryBus;
+use crate::cartride::Cartridge;
+use crate::cpu::CPU;
+use crate::model::Model;
+
+pub const CPU_CLOCK_HZ: u64 = 4_194_304;
+
+/// A `model` with `rom` inserted, starting at 0x0100 as if it had just booted
+pub fn load(rom: &Path, model: Model) -> Result<CPU, String> {
+    let path = rom.to_str().ok_or_else(|| format!("Unreadable ROM path: {}", rom.display()))?;
+    let cartridge = Cartridge::load(path)?;
+    Ok(CPU::new(MemoryBus::new(cartridge), model))
+}
+
+/// Every .gb file under `dir`, including subdirectories, in a stable order
+pub fn find_roms(dir: &Path) -> Vec<PathBuf> {
+    let mut roms = Vec::new();
+    let Ok(entries) = fs::read_dir(dir) else {
+        return roms;
+    };
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.is_dir() {
+            roms.extend(find_roms(&path));
+        } else if path.extension().is_some_and(|extension| extension == "gb") {
+            roms.push(path);
+        }
+    }
+    roms.sort();
+    roms
+}
+
+/// The directory named by `variable`, falling back to `tests/<default>` in the repository
+#[cfg(test)]
+fn suite_dir(variable: &str, default: &str) -> Option<PathBuf> {
+    let dir = std::env::var_os(variable)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join(default));
+    dir.is_dir().then_some(dir)
+}
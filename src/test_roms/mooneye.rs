@@ -0,0 +1,193 @@
+//! Mooneye's acceptance ROMs finish by executing `LD B,B` as a debugger breakpoint, with the
+//! Fibonacci numbers 3, 5, 8, 13, 21, 34 in B, C, D, E, H and L when they passed, and 0x42 in
+//! all of them when they failed.
+use std::path::Path;
+use super::CPU_CLOCK_HZ;
+use crate::bus::Bus;
+use crate::cpu::CPU;
+use crate::model::Model;
+
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 60;
+
+const BREAKPOINT: u8 = 0x40; // LD B,B
+const PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const FAILED: [u8; 6] = [0x42; 6];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    /// Hit the breakpoint with neither the pass nor the fail pattern in B/C/D/E/H/L
+    Unexpected([u8; 6]),
+    TimedOut,
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Passed => 0,
+            Outcome::Failed | Outcome::Unexpected(_) => 1,
+            Outcome::TimedOut => 2,
+        }
+    }
+}
+
+/// The hardware a ROM is meant for, from the suffix in its name (boot_regs-dmgABC, di_timing-GS, ...).
+/// None when it only runs on a Game Boy Color or Advance, which aren't emulated yet
+pub fn model_for(rom: &Path) -> Option<Model> {
+    let stem = rom.file_stem()?.to_string_lossy();
+    let Some((_, models)) = stem.rsplit_once('-') else {
+        return Some(Model::DMG);
+    };
+    // G is the DMG and MGB group, S the SGB and SGB2 one
+    if models.contains("dmgABC") || models.contains('G') {
+        Some(Model::DMG)
+    } else if models.contains("dmg0") {
+        Some(Model::DMG0)
+    } else if models.contains("mgb") {
+        Some(Model::MGB)
+    } else if models.contains("sgb2") {
+        Some(Model::SGB2)
+    } else if models.contains("sgb") || models.contains('S') {
+        Some(Model::SGB)
+    } else {
+        None
+    }
+}
+
+/// Runs `rom` on `model` until it reaches the breakpoint or `timeout_seconds` of emulated time have gone by
+pub fn run(rom: &Path, model: Model, timeout_seconds: u64) -> Result<Outcome, String> {
+    run_cpu(super::load(rom, model)?, timeout_seconds)
+}
+
+fn run_cpu(mut cpu: CPU, timeout_seconds: u64) -> Result<Outcome, String> {
+    let budget = timeout_seconds * CPU_CLOCK_HZ;
+    let mut elapsed = 0;
+    while elapsed < budget {
+        let state = cpu.state();
+        if cpu.bus().read_byte(state.pc()) == BREAKPOINT {
+            let registers = [state.b(), state.c(), state.d(), state.e(), state.h(), state.l()];
+            return Ok(match registers {
+                PASSED => Outcome::Passed,
+                FAILED => Outcome::Failed,
+                registers => Outcome::Unexpected(registers),
+            });
+        }
+        let cycles = match cpu.step() {
+            Ok(cycles) => cycles,
+            Err(error) => return Err(format!("{}\n{}", error, cpu.call_stack())),
+        };
+        elapsed += cycles as u64;
+    }
+    Ok(Outcome::TimedOut)
+}
+
+/// Runs `rom` from the command line on the model its name asks for, returning the process exit code
+pub fn run_headless(rom: &Path) -> i32 {
+    let Some(model) = model_for(rom) else {
+        eprintln!("{} needs a Game Boy Color or Advance", rom.display());
+        return Outcome::Failed.exit_code();
+    };
+    match run(rom, model, DEFAULT_TIMEOUT_SECONDS) {
+        Ok(outcome) => {
+            println!("{:?}", outcome);
+            outcome.exit_code()
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            Outcome::Failed.exit_code()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_rom;
+    use crate::bus::MemoryBus;
+    use crate::cartride::Cartridge;
+    use crate::test_roms::{find_roms, suite_dir};
+
+    fn run_source(source: &str) -> Outcome {
+        let rom = assemble_rom(source).unwrap_or_else(|error| panic!("{}", error));
+        let cpu = CPU::new(MemoryBus::new(Cartridge::from_rom(rom).unwrap()), Model::DMG);
+        run_cpu(cpu, 1).unwrap()
+    }
+
+    #[test]
+    fn breakpoint_reports_the_registers() {
+        let passed = run_source("
+                    jp main
+            org $150
+            main:   ld bc, $0305
+                    ld de, $080d
+                    ld hl, $1522
+                    ld b, b
+        ");
+        assert_eq!(passed, Outcome::Passed);
+
+        let failed = run_source("
+                    jp main
+            org $150
+            main:   ld a, $42
+                    ld b, a
+                    ld c, a
+                    ld d, a
+                    ld e, a
+                    ld h, a
+                    ld l, a
+                    ld b, b
+        ");
+        assert_eq!(failed, Outcome::Failed);
+    }
+
+    #[test]
+    fn breakpoint_byte_inside_a_prefixed_instruction_is_not_one() {
+        // bit 0, b is cb 40, stepping onto its second byte would stop with the fail pattern
+        let outcome = run_source("
+                    jp main
+            org $150
+            main:   ld a, $42
+                    ld b, a
+                    ld c, a
+                    ld d, a
+                    ld e, a
+                    ld h, a
+                    ld l, a
+                    bit 0, b
+                    ld bc, $0305
+                    ld de, $080d
+                    ld hl, $1522
+                    ld b, b
+        ");
+        assert_eq!(outcome, Outcome::Passed);
+    }
+
+    #[test]
+    fn mooneye_roms() {
+        let Some(dir) = suite_dir("MOONEYE_ROMS", "mooneye") else {
+            eprintln!("Mooneye test ROMs not found, set MOONEYE_ROMS to run them");
+            return;
+        };
+        let roms = find_roms(&dir);
+        let mut failures = Vec::new();
+        let mut passed = 0;
+        for rom in &roms {
+            let name = rom.strip_prefix(&dir).unwrap_or(rom).display();
+            let Some(model) = model_for(rom) else {
+                println!("skip {}", name);
+                continue;
+            };
+            match run(rom, model, DEFAULT_TIMEOUT_SECONDS) {
+                Ok(Outcome::Passed) => {
+                    passed += 1;
+                    println!("pass {}", name);
+                }
+                Ok(outcome) => failures.push(format!("{}: {:?}", name, outcome)),
+                Err(error) => failures.push(format!("{}: {}", name, error)),
+            }
+        }
+        println!("{} of {} Mooneye ROMs passed", passed, roms.len());
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
@@ -16,62 +16,171 @@ impl From<u8> for Frequency {
     }
 }
 
+/// DIV and TIMA are worked out from the bus clock when they're needed instead of being counted up
+/// every cycle, `sync` catches TIMA up and the bus schedules a sync for when it will overflow
+#[derive(Clone)]
 pub struct Timer {
-    pub divider: u16,    // Internal 16-bit counter (top 8 bits are DIV 0xFF04)
-    pub tima: u8,       // Timer Counter 0xFF05
+    divider_offset: u16, // the internal 16-bit counter (top 8 bits are DIV 0xFF04) minus the clock
+    pub tima: u8,       // Timer Counter 0xFF05, as of `synced_at`
     pub tma: u8,        // Timer Modulo 0xFF06
     pub tac: u8,        // Timer Control 0xFF07
-    internal_cycle: usize,
+    internal_cycle: u64,
+    synced_at: u64,
 }
 
 impl Timer {
     pub fn new() -> Timer {
         Timer {
-            divider: 0,
+            divider_offset: 0,
             tima: 0,
             tma: 0,
             tac: 0,
             internal_cycle: 0,
+            synced_at: 0,
         }
     }
 
-    pub fn update(&mut self, cycles: u8) -> bool {
-        let cycles = cycles as usize;
+    /// The 16-bit divider at T-cycle `now`, it always counts (16384Hz at the top byte)
+    pub fn divider(&self, now: u64) -> u16 {
+        self.divider_offset.wrapping_add(now as u16)
+    }
+
+    pub fn set_divider(&mut self, now: u64, value: u16) {
+        self.divider_offset = value.wrapping_sub(now as u16);
+    }
 
-        // 1. The Divider (DIV) always increments (16384Hz)
-        // It's effectively the 16-bit divider register's upper 8 bits.
-        self.divider = self.divider.wrapping_add(cycles as u16);
+    /// TIMA as it reads at `now`
+    pub fn tima(&self, now: u64) -> u8 {
+        let mut timer = self.clone();
+        timer.sync(now);
+        timer.tima
+    }
 
-        // 2. Check if Timer is enabled (Bit 2 of TAC)
-        if self.tac & 0b100 == 0 {
+    /// Counts TIMA up to T-cycle `now`, returns true when it overflowed along the way
+    pub fn sync(&mut self, now: u64) -> bool {
+        let elapsed = now - self.synced_at;
+        self.synced_at = now;
+        // Bit 2 of TAC enables the timer
+        if !self.enabled() {
             return false;
         }
+        self.internal_cycle += elapsed;
+        let period = self.period();
+        let mut increments = self.internal_cycle / period;
+        self.internal_cycle %= period;
+
+        let mut interrupt = false;
+        while increments > 0 {
+            let until_overflow = 0x100 - self.tima as u64;
+            if increments < until_overflow {
+                self.tima += increments as u8;
+                break;
+            }
+            // Overflow occurred, reload from Modulo and trigger the Timer interrupt
+            increments -= until_overflow;
+            self.tima = self.tma;
+            interrupt = true;
+        }
+        interrupt
+    }
+
+    /// When TIMA will next overflow if nothing is written to the timer before then, as of the last sync
+    pub fn next_overflow(&self) -> Option<u64> {
+        if !self.enabled() {
+            return None;
+        }
+        let until_overflow = (0x100 - self.tima as u64) * self.period();
+        Some(self.synced_at + until_overflow.saturating_sub(self.internal_cycle))
+    }
 
-        self.internal_cycle += cycles;
+    fn enabled(&self) -> bool {
+        self.tac & 0b100 != 0
+    }
 
-        // 3. Get threshold based on Frequency bits (Bit 0-1 of TAC)
-        let threshold = match self.tac & 0b11 {
+    /// T-cycles per TIMA increment, from the Frequency bits (Bit 0-1 of TAC)
+    fn period(&self) -> u64 {
+        match self.tac & 0b11 {
             0 => 1024, // 4096 Hz
             1 => 16,   // 262144 Hz
             2 => 64,   // 65536 Hz
             _ => 256,  // 16384 Hz
-        };
+        }
+    }
+}
 
-        let mut interrupt = false;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // 4. Increment TIMA based on internal cycle count
-        while self.internal_cycle >= threshold {
-            self.internal_cycle -= threshold;
+    fn started(tac: u8) -> Timer {
+        let mut timer = Timer::new();
+        timer.tac = 0b100 | tac;
+        timer
+    }
 
-            if self.tima == 0xFF {
-                // Overflow occurred
-                self.tima = self.tma; // Reload from Modulo
-                interrupt = true;     // Trigger Timer Interrupt
-            } else {
-                self.tima += 1;
-            }
-        }
+    #[test]
+    fn sync_counts_whole_periods_and_keeps_the_rest() {
+        let mut timer = started(0b01); // every 16 cycles
+        assert!(!timer.sync(40));
+        assert_eq!(timer.tima, 2);
+        assert!(!timer.sync(48));
+        assert_eq!(timer.tima, 3);
+    }
 
-        interrupt
+    #[test]
+    fn sync_reloads_from_tma_on_overflow() {
+        let mut timer = started(0b01);
+        timer.tima = 0xFE;
+        timer.tma = 0x80;
+        assert!(timer.sync(16 * 5)); // 0xFF, overflow, 0x81, 0x82, 0x83
+        assert_eq!(timer.tima, 0x83);
+    }
+
+    #[test]
+    fn a_tac_change_mid_period_keeps_the_partial_period() {
+        // like the bus does it, sync first and then write
+        let mut timer = started(0b01);
+        timer.sync(24);
+        assert_eq!(timer.tima, 1);
+        timer.tac = 0b110; // every 64 cycles, 8 of them already gone by
+        timer.sync(24 + 55);
+        assert_eq!(timer.tima, 1);
+        timer.sync(24 + 56);
+        assert_eq!(timer.tima, 2);
+    }
+
+    #[test]
+    fn nothing_counts_while_stopped() {
+        let mut timer = started(0b01);
+        timer.sync(8);
+        timer.tac = 0b001;
+        assert!(!timer.sync(1000));
+        assert_eq!(timer.tima, 0);
+        assert_eq!(timer.next_overflow(), None);
+        timer.tac = 0b101;
+        timer.sync(1008); // the 8 cycles from before the stop still count
+        assert_eq!(timer.tima, 1);
+    }
+
+    #[test]
+    fn next_overflow_is_when_sync_reports_it() {
+        let mut timer = started(0b10); // every 64 cycles
+        timer.tima = 0xFD;
+        timer.sync(100); // one increment and 36 cycles into the next
+        assert_eq!(timer.tima, 0xFE);
+        let at = timer.next_overflow().unwrap();
+        assert_eq!(at, 100 + 28 + 64);
+        assert!(!timer.clone().sync(at - 1));
+        assert!(timer.sync(at));
+        assert_eq!(timer.tima, timer.tma);
+    }
+
+    #[test]
+    fn next_overflow_follows_a_tac_change() {
+        let mut timer = started(0b01);
+        timer.sync(8);
+        assert_eq!(timer.next_overflow(), Some(256 * 16));
+        timer.tac = 0b100; // every 1024 cycles, still 8 cycles in
+        assert_eq!(timer.next_overflow(), Some(256 * 1024));
     }
-}
\ No newline at end of file
+}
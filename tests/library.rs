@@ -0,0 +1,51 @@
+//! Drives the emulator the way an outside tool would, through the library's public API only
+use std::cell::RefCell;
+use std::rc::Rc;
+use gb_emu::{BusDevice, CPU, Cartridge, Hooks, MemoryBus, Model};
+
+/// A ROM-only cartridge running `code` from the 0x0100 entry point
+fn cpu_running(code: &[u8]) -> CPU {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + code.len()].copy_from_slice(code);
+    CPU::new(MemoryBus::new(Cartridge::from_rom(rom).unwrap()), Model::DMG)
+}
+
+struct Port(Rc<RefCell<Vec<u8>>>);
+
+impl BusDevice for Port {
+    fn read(&self, _address: u16) -> u8 {
+        0x5A
+    }
+
+    fn write(&mut self, _address: u16, value: u8) {
+        self.0.borrow_mut().push(value);
+    }
+}
+
+#[test]
+fn tools_can_watch_the_cpu_and_extend_the_bus() {
+    let mut cpu = cpu_running(&[
+        0xCD, 0x08, 0x01, // call $0108
+        0x76,             // halt
+        0, 0, 0, 0,
+        0xF0, 0x80,       // ldh a, [$ff80]
+        0xE0, 0x81,       // ldh [$ff81], a
+        0xC9,             // ret
+    ]);
+    let written = Rc::new(RefCell::new(Vec::new()));
+    cpu.bus_mut().map_device(0xFF80..=0xFF81, Port(written.clone()));
+    let steps = Rc::new(RefCell::new(0));
+    let counted = steps.clone();
+    cpu.set_hooks(Some(Hooks::new().on_after_step(move |_, _| *counted.borrow_mut() += 1)));
+
+    cpu.step().unwrap();
+    let frame = cpu.call_stack().frames()[0];
+    assert_eq!((frame.caller, frame.target), (0x0100, 0x0108));
+    while !cpu.state().halted() {
+        cpu.step().unwrap();
+    }
+    assert_eq!(cpu.state().a(), 0x5A);
+    assert_eq!(*written.borrow(), [0x5A]);
+    assert_eq!(*steps.borrow(), 5);
+    assert!(cpu.call_stack().frames().is_empty());
+}